@@ -2,8 +2,16 @@ fn main() {
     let envs = [
         ("APP_NAME", "rsdish"), 
         ("APP_CONFIG_NAME", "rsdish.config"),
+        ("REGISTRY_NAME", "rsdish.registry.json"),
+        ("REGISTRY_TMP_SUFFIX", ".tmp"),
         ("CABINET_CONFIG_NAME", "rsdish.cabinet.toml"),
+        ("CABINET_INDEX_NAME", "rsdish.cabinet.index"),
+        ("STORAGE_MARKER_NAME", "rsdish.storage.toml"),
         ("SRC_IGNORE_NAME", ".srcignore"),
+        ("SYNC_TMP_SUFFIX", ".rsdish-tmp"),
+        ("MANIFEST_DIR_NAME", ".rsdish-manifest"),
+        ("TRASH_DIR_NAME", ".rsdish-trash"),
+        ("TRASH_META_NAME", "rsdish.trash.toml"),
     ];
     
     for (k, v) in envs {
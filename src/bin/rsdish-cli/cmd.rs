@@ -3,4 +3,6 @@ pub mod cabinet;
 pub mod storage;
 pub mod group;
 pub mod config;
-pub mod preset;
\ No newline at end of file
+pub mod preset;
+pub mod trash;
+pub mod status;
\ No newline at end of file
@@ -1,7 +1,15 @@
-use std::{env, fs, path::PathBuf};
+use std::{env, path::PathBuf};
 
 use clap::{Args, Subcommand};
-use rsdish::phy::cab_conf::{CabinetConfig, default_cabinet_config, default_membership};
+use rsdish::phy::{
+    cab::{init_cabinet, join_cabinet, migrate_cabinet},
+    cab_conf::CABINET_CONFIG_VERSION,
+};
+
+use crate::{
+    cmd::storage::storages,
+    err::{CliError, keep_first},
+};
 
 #[derive(Debug, Args)]
 #[command(about = "Initialization of cabinet, membership management.")]
@@ -14,6 +22,7 @@ pub struct CabinetCmd {
 pub enum CabinetSubcommand {
     Init(CabinetInitArgs),
     Join(CabinetJoinArgs),
+    Migrate(CabinetMigrateArgs),
 }
 
 #[derive(Debug, Args)]
@@ -27,62 +36,97 @@ pub struct CabinetJoinArgs {
     pub group_uuids: Vec<String>,
 }
 
-pub fn handle_cabinet(cmd: CabinetCmd) {
+#[derive(Debug, Args)]
+#[command(about = "Rewrite cabinet config(s) of older versions in the current version.")]
+pub struct CabinetMigrateArgs {
+    #[arg(
+        value_name = "cabinet_path(s)",
+        conflicts_with = "all",
+        help = "Cabinets to migrate, the current one if none given"
+    )]
+    pub cabinet_paths: Vec<String>,
+    #[arg(long, help = "Migrate every cabinet on scanned storages")]
+    pub all: bool,
+    #[arg(long, help = "Only print which configs would be migrated")]
+    pub dry_run: bool,
+}
+
+pub fn handle_cabinet(cmd: CabinetCmd) -> Result<(), CliError> {
     match cmd.subcmd {
         CabinetSubcommand::Init(child) => handle_cabinet_init(child),
         CabinetSubcommand::Join(child) => handle_cabinet_join(child),
+        CabinetSubcommand::Migrate(child) => handle_cabinet_migrate(child),
     }
 }
 
-pub fn handle_cabinet_init(_args: CabinetInitArgs) {
-    let current_dir = env::current_dir().unwrap();
-
-    let cab_conf = default_cabinet_config();
-
-    let toml_str = toml::to_string(&cab_conf).unwrap();
-
-    let config_path: PathBuf = current_dir.join(env!("CABINET_CONFIG_NAME"));
-
-    // Check if config file already exists
-    if config_path.exists() {
-        eprintln!(
-            "Error: config file already exists at {}",
-            config_path.display()
-        );
-        std::process::exit(1);
-    }
+pub fn handle_cabinet_init(_args: CabinetInitArgs) -> Result<(), CliError> {
+    let current_dir = env::current_dir()?;
 
-    fs::write(&config_path, toml_str).unwrap();
+    let cab = init_cabinet(&current_dir)?;
 
     println!(
         "Created default cabinet config at {}",
-        config_path.display()
+        cab.cab_info.conf_abs_path.display()
     );
+    Ok(())
 }
 
-pub fn handle_cabinet_join(args: CabinetJoinArgs) {
-    let current_dir = env::current_dir().unwrap();
-    let config_path: PathBuf = current_dir.join(env!("CABINET_CONFIG_NAME"));
-
-    let cab_conf_str = fs::read_to_string(&config_path).unwrap();
-    let mut cab_conf: CabinetConfig = toml::from_str(&cab_conf_str).unwrap();
-
-    for uuid in &args.group_uuids {
-        let mut new_member = default_membership();
-        new_member.group_uuid = uuid.clone();
-        println!("Added new member with uuid {}", uuid);
-        cab_conf.memberships.push(new_member);
-    }
+pub fn handle_cabinet_join(args: CabinetJoinArgs) -> Result<(), CliError> {
+    let current_dir = env::current_dir()?;
 
     // default behaviour: rsdish cabinet join will default join a random membership
-    if args.group_uuids.is_empty() {
-        let new_member = default_membership();
-        println!("Added new member with uuid {}", new_member.group_uuid);
-        cab_conf.memberships.push(new_member);
+    let joined = join_cabinet(&current_dir, &args.group_uuids)?;
+    for uuid in joined {
+        println!("Added new member with uuid {}", uuid);
     }
 
-    let toml_str = toml::to_string(&cab_conf).unwrap();
-    fs::write(&config_path, toml_str).unwrap();
+    println!(
+        "Updated cabinet config at {}",
+        current_dir.join(env!("CABINET_CONFIG_NAME")).display()
+    );
+    Ok(())
+}
 
-    println!("Updated cabinet config at {}", config_path.display());
+pub fn handle_cabinet_migrate(args: CabinetMigrateArgs) -> Result<(), CliError> {
+    let cab_paths: Vec<PathBuf> = if args.all {
+        storages(false)
+            .into_iter()
+            .flat_map(|stg| stg.cabs)
+            .map(|cab| cab.cab_info.abs_path)
+            .collect()
+    } else if args.cabinet_paths.is_empty() {
+        vec![env::current_dir()?]
+    } else {
+        args.cabinet_paths.iter().map(PathBuf::from).collect()
+    };
+
+    let mut result = Ok(());
+    for cab_path in cab_paths {
+        match migrate_cabinet(&cab_path, args.dry_run) {
+            Ok(Some(CABINET_CONFIG_VERSION)) if args.dry_run => {
+                println!("Would assign a cabinet uuid to {}", cab_path.display())
+            }
+            Ok(Some(CABINET_CONFIG_VERSION)) => {
+                println!("Assigned a cabinet uuid to {}", cab_path.display())
+            }
+            Ok(Some(version)) if args.dry_run => println!(
+                "Would migrate {} from version {} to {}",
+                cab_path.display(),
+                version,
+                CABINET_CONFIG_VERSION
+            ),
+            Ok(Some(version)) => println!(
+                "Migrated {} from version {} to {}",
+                cab_path.display(),
+                version,
+                CABINET_CONFIG_VERSION
+            ),
+            Ok(None) => println!("{} is up to date", cab_path.display()),
+            Err(e) => {
+                eprintln!("Failed to migrate cabinet {}: {}", cab_path.display(), e);
+                keep_first(&mut result, e);
+            }
+        }
+    }
+    result
 }
@@ -1,11 +1,14 @@
 use clap::{Args};
 use rsdish::user::user_conf::user_conf_path;
 
+use crate::err::CliError;
+
 #[derive(Debug, Args)]
 #[command(about = "Print config path.")]
 pub struct ConfigCmd {}
 
-pub fn handle_config(_: ConfigCmd) {
-  let user_conf_path = user_conf_path().unwrap();
+pub fn handle_config(_: ConfigCmd) -> Result<(), CliError> {
+  let user_conf_path = user_conf_path()?;
   println!("{:?}", user_conf_path);
-}
\ No newline at end of file
+  Ok(())
+}
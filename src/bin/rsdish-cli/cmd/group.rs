@@ -1,13 +1,33 @@
-use std::{collections::BTreeMap, fs, io::{self, Read}, path::PathBuf};
+use std::{collections::BTreeMap, fs, io::{self, Read}, path::{Path, PathBuf}, time::SystemTime};
 
-use clap::{Args, Subcommand};
-use rsdish::logi::{
-    gp::{Group, build_group_map_from_storages},
-    vmem::build_virtual_member_from_group,
+use clap::{Args, Subcommand, ValueEnum};
+use serde::Serialize;
+use rsdish::{
+    logi::{
+        conflict::{Conflict, ConflictError, ConflictPolicy, WinReason},
+        gp::{Group, GroupSelectError, build_group_map_from_storages, select_groups},
+        plan::{GroupSyncPlan, SyncAction},
+        report::{Operation, Report},
+        sync::SyncOptions,
+        vmem::{VirtualMember, VmemOptions, build_virtual_member_from_group},
+    },
+    phy::{
+        cab::CabinetError,
+        cab_conf::{CabinetConfigError, CompareMode, is_valid_group_name},
+    },
+    user::{
+        registry::{RegistryMember, registry, update_registry},
+        user_conf::user_conf,
+    },
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::cmd::storage::storages;
+use crate::{
+    cmd::storage::storages,
+    err::{CliError, keep_first},
+    output::{OutputFormat, format_ago, format_offline, print_serialized},
+    progress::BarObserver,
+};
 
 #[derive(Debug, Args)]
 #[command(about = "Group operations.")]
@@ -23,6 +43,8 @@ pub enum GroupSubcommand {
     Link(GroupLinkArgs),
     Clean(GroupCleanArgs),
     Exec(GroupExecArgs),
+    Conflicts(GroupConflictsArgs),
+    Rename(GroupRenameArgs),
 }
 
 #[derive(Debug, Args)]
@@ -37,10 +59,60 @@ pub struct GroupListArgs {
 pub struct GroupSyncArgs {
     #[arg(long)]
     pub strict: bool,
+    #[arg(long, value_enum, help = "Override how dst files are compared with src")]
+    pub compare: Option<CompareArg>,
+    #[arg(long, help = "Print the sync plan without copying anything")]
+    pub dry_run: bool,
+    #[arg(long, help = "Ignore cabinet indexes, reading every folder and hashing files again")]
+    pub rescan: bool,
+    #[arg(long, value_enum, help = "Override how conflicting src versions are resolved")]
+    pub conflict_policy: Option<ConflictPolicyArg>,
+    #[arg(short, long, default_value_t = 1, help = "Filesystems written at the same time")]
+    pub jobs: usize,
+    #[arg(long, default_value_t = 1, help = "Files written at the same time on one filesystem")]
+    pub writers_per_storage: usize,
+    #[arg(long, value_name = "file", help = "Write a json report of every file to the given path")]
+    pub report: Option<PathBuf>,
+    #[arg(long, help = "On a full dst storage, copy what fits, highest priority first")]
+    pub partial: bool,
     #[arg(long)]
     pub all: bool,
-    #[arg(value_name = "group_uuid(s)")]
-    pub group_uuids: Vec<String>,
+    #[arg(value_name = "group(s)", help = "Group uuids, uuid prefixes, names or globs")]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConflictPolicyArg {
+    PriorityWins,
+    NewestWins,
+    KeepBoth,
+    Abort,
+}
+
+impl From<ConflictPolicyArg> for ConflictPolicy {
+    fn from(value: ConflictPolicyArg) -> Self {
+        match value {
+            ConflictPolicyArg::PriorityWins => ConflictPolicy::PriorityWins,
+            ConflictPolicyArg::NewestWins => ConflictPolicy::NewestWins,
+            ConflictPolicyArg::KeepBoth => ConflictPolicy::KeepBoth,
+            ConflictPolicyArg::Abort => ConflictPolicy::Abort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CompareArg {
+    Meta,
+    Hash,
+}
+
+impl From<CompareArg> for CompareMode {
+    fn from(value: CompareArg) -> Self {
+        match value {
+            CompareArg::Meta => CompareMode::Meta,
+            CompareArg::Hash => CompareMode::Hash,
+        }
+    }
 }
 
 #[derive(Debug, Args)]
@@ -48,8 +120,12 @@ pub struct GroupSyncArgs {
 pub struct GroupLinkArgs {
     #[arg(long)]
     pub all: bool,
-    #[arg(value_name = "group_uuid(s)")]
-    pub group_uuids: Vec<String>,
+    #[arg(long, help = "Ignore cabinet indexes, reading every folder and hashing files again")]
+    pub rescan: bool,
+    #[arg(long, value_name = "file", help = "Write a json report of every file to the given path")]
+    pub report: Option<PathBuf>,
+    #[arg(value_name = "group(s)", help = "Group uuids, uuid prefixes, names or globs")]
+    pub groups: Vec<String>,
 }
 
 #[derive(Debug, Args)]
@@ -57,8 +133,12 @@ pub struct GroupLinkArgs {
 pub struct GroupCleanArgs {
     #[arg(long)]
     pub all: bool,
-    #[arg(value_name = "group_uuid(s)")]
-    pub group_uuids: Vec<String>,
+    #[arg(long, help = "Ignore cabinet indexes, reading every folder and hashing files again")]
+    pub rescan: bool,
+    #[arg(long, value_name = "file", help = "Write a json report of every file to the given path")]
+    pub report: Option<PathBuf>,
+    #[arg(value_name = "group(s)", help = "Group uuids, uuid prefixes, names or globs")]
+    pub groups: Vec<String>,
 }
 
 #[derive(Debug, Args)]
@@ -68,109 +148,469 @@ pub struct GroupExecArgs {
     pub input: PathBuf,
     #[arg(long)]
     pub all: bool,
-    #[arg(value_name = "group_uuid(s)")]
-    pub group_uuids: Vec<String>,
+    #[arg(value_name = "group(s)", help = "Group uuids, uuid prefixes, names or globs")]
+    pub groups: Vec<String>,
 }
 
-pub fn handle_group(cmd: GroupCmd) {
+#[derive(Debug, Args)]
+#[command(about = "List files whose srcs hold divergent versions.")]
+pub struct GroupConflictsArgs {
+    #[arg(long, help = "Compare src contents instead of size and mtime, to rule out copies made without keeping mtime")]
+    pub hash: bool,
+    #[arg(long, help = "Ignore cabinet indexes, reading every folder and hashing files again")]
+    pub rescan: bool,
+    #[arg(long)]
+    pub all: bool,
+    #[arg(value_name = "group(s)", help = "Group uuids, uuid prefixes, names or globs")]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Name a group in every member cabinet.")]
+pub struct GroupRenameArgs {
+    #[arg(value_name = "group", help = "Group uuid, uuid prefix or current name")]
+    pub group: String,
+    pub name: String,
+    #[arg(short, long)]
+    pub description: Option<String>,
+}
+
+pub fn handle_group(cmd: GroupCmd, format: OutputFormat) -> Result<(), CliError> {
     match cmd.subcmd {
-        GroupSubcommand::List(child) => handle_group_list(child),
+        GroupSubcommand::List(child) => handle_group_list(child, format),
         GroupSubcommand::Sync(child) => handle_group_sync(child),
         GroupSubcommand::Link(child) => handle_group_link(child),
         GroupSubcommand::Clean(child) => handle_group_clean(child),
         GroupSubcommand::Exec(child) => handle_group_exec(child),
+        GroupSubcommand::Conflicts(child) => handle_group_conflicts(child),
+        GroupSubcommand::Rename(child) => handle_group_rename(child),
+    }
+}
+
+#[derive(Serialize)]
+struct GroupView<'a> {
+    #[serde(flatten)]
+    gp: &'a Group,
+    // path -> src cabinet it resolved to and why, only with --verbose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved: Option<BTreeMap<PathBuf, Resolved>>,
+    // known members whose drive is not plugged in
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    offline: Vec<&'a RegistryMember>,
+}
+
+#[derive(Serialize)]
+struct Resolved {
+    cabinet: PathBuf,
+    reason: WinReason,
+}
+
+// The src cabinet every path of gp resolves to and why, none if the group
+// is offline or its virtual member can't be built.
+fn resolve_paths(gp: &Group, vmem_opts: &VmemOptions) -> Option<BTreeMap<PathBuf, Resolved>> {
+    if gp.mems.is_empty() {
+        return None;
     }
+    let vmem = build_vmem(gp, vmem_opts).ok()?;
+    let cab_paths: BTreeMap<String, &Path> = gp
+        .mems
+        .iter()
+        .map(|mem| (mem.mem_info.cab_id(), mem.mem_info.cab_info.abs_path.as_path()))
+        .collect();
+    let resolved = vmem
+        .virtual_tree
+        .keys()
+        .filter_map(|path| vmem.resolution(path).map(|res| (path, res)))
+        .map(|(path, (leaf, reason))| {
+            let cabinet = cab_paths
+                .get(&leaf.cab_id)
+                .map_or_else(|| PathBuf::from(&leaf.cab_id), |p| p.to_path_buf());
+            (path.clone(), Resolved { cabinet, reason })
+        })
+        .collect();
+    Some(resolved)
 }
 
-pub fn handle_group_list(args: GroupListArgs) {
-    let stgs = storages();
-    let gp_map = build_group_map_from_storages(&stgs);
-    let gps: Vec<Group> = gp_map.into_values().collect();
+pub fn handle_group_list(args: GroupListArgs, format: OutputFormat) -> Result<(), CliError> {
+    let gp_map = group_map(false);
+    let reg = registry();
+    // groups with every member offline are only known to the registry
+    let offline_gps = reg.offline_groups(&gp_map);
+    let gps: Vec<Group> = gp_map.into_values().chain(offline_gps).collect();
+
+    let vmem_opts = vmem_options(false, false);
+    if format != OutputFormat::Table {
+        let gp_views: Vec<GroupView> = gps
+            .iter()
+            .map(|gp| GroupView {
+                gp,
+                resolved: args
+                    .verbose
+                    .then(|| resolve_paths(gp, &vmem_opts))
+                    .flatten(),
+                offline: reg.offline_members(gp),
+            })
+            .collect();
+        print_serialized(&gp_views, format);
+        return Ok(());
+    }
 
     if !args.verbose {
         for gp in &gps {
-            println!("Group: {:?}", gp.gp_info.gp_uuid);
-            let mem_count = gp.mems.len();
-            for (i, mem) in gp.mems.iter().enumerate() {
-                let prefix = if i == mem_count - 1 {
+            println!("Group: {}", group_label(gp));
+            let lines: Vec<String> = gp
+                .mems
+                .iter()
+                .map(|mem| format!("Member: {:?}", mem.mem_info.cab_info.abs_path))
+                .chain(reg.offline_members(gp).iter().map(|reg_mem| {
+                    format!(
+                        "Member: {:?} ({}, last seen {})",
+                        reg_mem.cab_abs_path,
+                        format_offline(reg_mem),
+                        format_ago(Some(reg_mem.last_seen))
+                    )
+                }))
+                .collect();
+            let line_count = lines.len();
+            for (i, line) in lines.iter().enumerate() {
+                let prefix = if i == line_count - 1 {
                     "└──"
                 } else {
                     "├──"
                 };
-                println!("{} Member: {:?}", prefix, mem.mem_info.cab_info.abs_path);
+                println!("{} {}", prefix, line);
             }
         }
-        return;
+        return Ok(());
     }
 
     for gp in gps {
-        println!("{:#?}", gp);
+        println!("Group: {}", group_label(&gp));
+        if !gp.gp_info.description.is_empty() {
+            println!("  Description: {}", gp.gp_info.description);
+        }
+        for mem in &gp.mems {
+            let mem_conf = &mem.mem_info.mem_conf;
+            println!(
+                "  Member: {:?} (priority: {}, src: {}, dst: {}, link: {})",
+                mem.mem_info.cab_info.abs_path,
+                mem_conf.priority,
+                mem_conf.src_option.enable,
+                mem_conf.dst_option.enable,
+                mem_conf.link_option.enable
+            );
+        }
+        for reg_mem in reg.offline_members(&gp) {
+            println!(
+                "  Member: {:?} ({}, priority: {}, src: {}, dst: {}, link: {}, last seen {}, last synced {})",
+                reg_mem.cab_abs_path,
+                format_offline(reg_mem),
+                reg_mem.priority,
+                reg_mem.src,
+                reg_mem.dst,
+                reg_mem.link,
+                format_ago(Some(reg_mem.last_seen)),
+                format_ago(reg_mem.last_synced)
+            );
+        }
+        // the src each path resolved to
+        for (path, res) in resolve_paths(&gp, &vmem_opts).unwrap_or_default() {
+            println!("  {:?} <- {:?} ({})", path, res.cabinet, res.reason);
+        }
     }
+    Ok(())
 }
 
-fn select_groups<'a>(
+// Groups of every online member.
+// write_ids only for commands that write to the members anyway
+pub fn group_map(write_ids: bool) -> BTreeMap<String, Group> {
+    let stgs = storages(write_ids);
+    build_group_map_from_storages(&stgs)
+}
+
+// Record every online member as seen, only from sync and link so listing
+// and status leave the registry untouched.
+fn observe_groups(gp_map: &BTreeMap<String, Group>) {
+    update_registry(|reg| reg.observe(gp_map.values(), SystemTime::now()));
+}
+
+// ex: "0199ebad-44ad-78a2-..." (photos)
+fn group_label(gp: &Group) -> String {
+    if gp.gp_info.name.is_empty() {
+        format!("{:?}", gp.gp_info.gp_uuid)
+    } else {
+        format!("{:?} ({})", gp.gp_info.gp_uuid, gp.gp_info.name)
+    }
+}
+
+pub fn resolve_groups<'a>(
     gp_map: &'a BTreeMap<String, Group>,
     all: bool,
-    select_uuids: &Vec<String>,
-) -> Vec<&'a Group> {
-    let select_gps: Vec<&Group> = if !all {
-        select_uuids
-            .iter()
-            .filter_map(|uuid| match gp_map.get(uuid) {
-                Some(gp) => Some(gp),
-                None => {
-                    error!("Invalid given uuid {}", uuid);
-                    None
-                }
-            })
-            .collect()
-    } else {
-        gp_map.values().collect()
-    };
+    selectors: &[String],
+) -> Result<Vec<&'a Group>, CliError> {
+    if all {
+        return Ok(gp_map.values().collect());
+    }
+    let mut select_gps: Vec<&Group> = vec![];
+    for selector in selectors {
+        for gp in select_groups(gp_map, selector)? {
+            // a group given twice runs once
+            if !select_gps
+                .iter()
+                .any(|g| g.gp_info.gp_uuid == gp.gp_info.gp_uuid)
+            {
+                select_gps.push(gp);
+            }
+        }
+    }
+    Ok(select_gps)
+}
 
-    select_gps
+// Like resolve_groups, with the filesystems the groups write to probed first.
+fn resolve_groups_to_write<'a>(
+    gp_map: &'a mut BTreeMap<String, Group>,
+    all: bool,
+    selectors: &[String],
+) -> Result<Vec<&'a Group>, CliError> {
+    let select_uuids: Vec<String> = resolve_groups(gp_map, all, selectors)?
+        .iter()
+        .map(|gp| gp.gp_info.gp_uuid.clone())
+        .collect();
+    let mut probed = BTreeMap::new();
+    for uuid in &select_uuids {
+        if let Some(gp) = gp_map.get_mut(uuid) {
+            gp.probe_fs_caps(&mut probed);
+        }
+    }
+    let gp_map: &'a BTreeMap<String, Group> = gp_map;
+    Ok(select_uuids.iter().filter_map(|uuid| gp_map.get(uuid)).collect())
 }
 
-pub fn handle_group_sync(args: GroupSyncArgs) {
-    let stgs = storages();
-    let gp_map = build_group_map_from_storages(&stgs);
-    let select_gps: Vec<&Group> = select_groups(&gp_map, args.all, &args.group_uuids);
+pub fn handle_group_sync(args: GroupSyncArgs) -> Result<(), CliError> {
+    let mut gp_map = group_map(!args.dry_run);
+    if !args.dry_run {
+        observe_groups(&gp_map);
+    }
+    let select_gps: Vec<&Group> = if args.dry_run {
+        resolve_groups(&gp_map, args.all, &args.groups)?
+    } else {
+        resolve_groups_to_write(&mut gp_map, args.all, &args.groups)?
+    };
 
     let sync_target_uuids: Vec<_> = select_gps.iter().map(|g| &g.gp_info.gp_uuid).collect();
     info!("run sync for groups {:?}", sync_target_uuids);
 
+    let sync_opts = SyncOptions {
+        strict: args.strict,
+        compare_mode: args.compare.map(CompareMode::from),
+        jobs: args.jobs,
+        writers_per_storage: args.writers_per_storage,
+        partial: args.partial,
+    };
+    let mut base_opts = vmem_options(args.rescan, !args.dry_run);
+    if let Some(conflict_policy) = args.conflict_policy {
+        base_opts.conflict_policy = conflict_policy.into();
+    }
+
+    let mut result = Ok(());
+    let mut reports = vec![];
     for select_gp in select_gps {
-        let vmem = build_virtual_member_from_group(select_gp);
-        select_gp.sync_from_vmem(&vmem, args.strict);
+        let vmem_opts = VmemOptions {
+            hash: sync_opts.needs_hash(select_gp),
+            ..base_opts.clone()
+        };
+        let vmem = match build_vmem(select_gp, &vmem_opts) {
+            Ok(vmem) => vmem,
+            Err(e) => {
+                keep_first(&mut result, e);
+                continue;
+            }
+        };
+        if args.dry_run {
+            print_sync_plan(&select_gp.plan_sync_from_vmem(&vmem, &sync_opts));
+            continue;
+        }
+        match select_gp.sync_from_vmem(&vmem, &sync_opts, &BarObserver::new()) {
+            Ok(report) => {
+                update_registry(|reg| reg.record_sync(select_gp, &report, SystemTime::now()));
+                reports.push(report);
+            }
+            Err(e) => {
+                error!("Skip group {}: {}", select_gp.gp_info.gp_uuid, e);
+                keep_first(&mut result, e);
+            }
+        }
+    }
+    finish_reports(Operation::Sync, &reports, args.report.as_deref(), result)
+}
+
+// vmem options from the user config, save_index for commands that write anyway
+pub fn vmem_options(rescan: bool, save_index: bool) -> VmemOptions {
+    let user_conf = user_conf();
+    VmemOptions {
+        rescan,
+        save_index,
+        conflict_policy: user_conf.conflict_policy,
+        tie_break: user_conf.tie_break,
+        ..Default::default()
+    }
+}
+
+pub fn build_vmem(gp: &Group, vmem_opts: &VmemOptions) -> Result<VirtualMember, ConflictError> {
+    build_virtual_member_from_group(gp, vmem_opts).inspect_err(|e| {
+        error!("Skip group {}: {}", gp.gp_info.gp_uuid, e);
+        let ConflictError::Abort(conflicts) = e;
+        print_conflicts(conflicts);
+    })
+}
+
+fn print_conflicts(conflicts: &[Conflict]) {
+    for conflict in conflicts {
+        println!("Conflict: {:?}", conflict.file_rel_path);
+        for version in &conflict.versions {
+            let mark = if version.file_abs_path == conflict.winner_abs_path {
+                "*"
+            } else {
+                " "
+            };
+            println!(
+                "  {} priority: {}, size: {}, mtime: {}, src: {:?}",
+                mark,
+                version.priority,
+                version.size,
+                humantime::format_rfc3339_seconds(version.mtime),
+                version.file_abs_path
+            );
+        }
+        for kept_rel_path in &conflict.kept_rel_paths {
+            println!("  + kept as {:?}", kept_rel_path);
+        }
+    }
+}
+
+pub fn handle_group_conflicts(args: GroupConflictsArgs) -> Result<(), CliError> {
+    let gp_map = group_map(false);
+    let select_gps: Vec<&Group> = resolve_groups(&gp_map, args.all, &args.groups)?;
+
+    // priority-wins only reports, nothing is rearranged
+    let vmem_opts = VmemOptions {
+        hash: args.hash,
+        conflict_policy: ConflictPolicy::PriorityWins,
+        ..vmem_options(args.rescan, false)
+    };
+
+    for select_gp in select_gps {
+        let vmem = build_vmem(select_gp, &vmem_opts)?;
+        println!(
+            "Group: {:?} ({} conflicts)",
+            select_gp.gp_info.gp_uuid,
+            vmem.conflicts.len()
+        );
+        print_conflicts(&vmem.conflicts);
+    }
+    Ok(())
+}
+
+fn print_sync_plan(gp_plan: &GroupSyncPlan) {
+    println!("Group: {:?}", gp_plan.gp.gp_info.gp_uuid);
+    for mem_plan in &gp_plan.mem_plans {
+        println!(
+            "Dst: {:?} (copy: {}, overwrite: {}, delete: {}, skip: {})",
+            mem_plan.dst.mem_info.cab_info.abs_path,
+            mem_plan.count(SyncAction::Copy),
+            mem_plan.count(SyncAction::Overwrite),
+            mem_plan.count(SyncAction::Quarantine) + mem_plan.count(SyncAction::Delete),
+            mem_plan.count(SyncAction::Skip),
+        );
+        for step in &mem_plan.steps {
+            println!(
+                "  {:<10} {:?} ({})",
+                step.action, step.file_rel_path, step.reason
+            );
+        }
+    }
+    for check in gp_plan.space_checks().iter().filter(|check| !check.fits()) {
+        println!("Warning: not enough free space, {}", check);
     }
 }
 
-pub fn handle_group_link(args: GroupLinkArgs) {
-    let stgs = storages();
-    let gp_map = build_group_map_from_storages(&stgs);
-    let select_gps: Vec<&Group> = select_groups(&gp_map, args.all, &args.group_uuids);
+pub fn handle_group_link(args: GroupLinkArgs) -> Result<(), CliError> {
+    let mut gp_map = group_map(true);
+    observe_groups(&gp_map);
+    let select_gps: Vec<&Group> = resolve_groups_to_write(&mut gp_map, args.all, &args.groups)?;
 
     let link_target_uuids: Vec<_> = select_gps.iter().map(|g| &g.gp_info.gp_uuid).collect();
     info!("run link for groups {:?}", link_target_uuids);
 
+    let vmem_opts = vmem_options(args.rescan, true);
+
+    let mut result = Ok(());
+    let mut reports = vec![];
     for select_gp in select_gps {
-        let vmem = build_virtual_member_from_group(select_gp);
-        select_gp.link_from_vmem(&vmem);
+        match build_vmem(select_gp, &vmem_opts) {
+            Ok(vmem) => {
+                let report = select_gp.link_from_vmem(&vmem);
+                print_report_summary(&report);
+                reports.push(report);
+            }
+            Err(e) => keep_first(&mut result, e),
+        }
     }
+    finish_reports(Operation::Link, &reports, args.report.as_deref(), result)
 }
 
-pub fn handle_group_clean(args: GroupCleanArgs) {
-    let stgs = storages();
-    let gp_map = build_group_map_from_storages(&stgs);
-    let select_gps: Vec<&Group> = select_groups(&gp_map, args.all, &args.group_uuids);
+pub fn handle_group_clean(args: GroupCleanArgs) -> Result<(), CliError> {
+    let gp_map = group_map(true);
+    let select_gps: Vec<&Group> = resolve_groups(&gp_map, args.all, &args.groups)?;
 
     let link_target_uuids: Vec<_> = select_gps.iter().map(|g| &g.gp_info.gp_uuid).collect();
     info!("run clean for groups {:?}", link_target_uuids);
 
+    let vmem_opts = vmem_options(args.rescan, true);
+
+    let mut result = Ok(());
+    let mut reports = vec![];
     for select_gp in select_gps {
-        let vmem = build_virtual_member_from_group(select_gp);
-        select_gp.clean_from_vmem(&vmem);
+        match build_vmem(select_gp, &vmem_opts) {
+            Ok(vmem) => {
+                let report = select_gp.clean_from_vmem(&vmem);
+                print_report_summary(&report);
+                reports.push(report);
+            }
+            Err(e) => keep_first(&mut result, e),
+        }
     }
+    finish_reports(Operation::Clean, &reports, args.report.as_deref(), result)
+}
+
+fn print_report_summary(report: &Report) {
+    println!(
+        "Group: {:?} ({}: {}, skipped: {}, failed: {})",
+        report.gp_uuid, report.operation, report.done, report.skipped, report.failed
+    );
+}
+
+// Write the reports when asked to, then fail if any file failed.
+fn finish_reports(
+    operation: Operation,
+    reports: &[Report],
+    report_path: Option<&Path>,
+    mut result: Result<(), CliError>,
+) -> Result<(), CliError> {
+    if let Some(report_path) = report_path {
+        let written = serde_json::to_string_pretty(reports)
+            .map_err(io::Error::other)
+            .and_then(|s| fs::write(report_path, s));
+        if let Err(e) = written {
+            keep_first(&mut result, e);
+        }
+    }
+
+    let failed: u64 = reports.iter().map(|report| report.failed).sum();
+    if failed > 0 {
+        keep_first(&mut result, CliError::Failed(operation, failed));
+    }
+    result
 }
 
 fn read_input(input: &PathBuf) -> io::Result<String> {
@@ -183,11 +623,64 @@ fn read_input(input: &PathBuf) -> io::Result<String> {
     }
 }
 
-pub fn handle_group_exec(args: GroupExecArgs) {
-    let stgs = storages();
-    let gp_map = build_group_map_from_storages(&stgs);
-    let select_gps: Vec<&Group> = select_groups(&gp_map, args.all, &args.group_uuids);
-    let script = read_input(&args.input).unwrap();
+pub fn handle_group_exec(args: GroupExecArgs) -> Result<(), CliError> {
+    let gp_map = group_map(false);
+    let select_gps: Vec<&Group> = resolve_groups(&gp_map, args.all, &args.groups)?;
+    let script = read_input(&args.input).map_err(|e| CliError::Script(args.input.clone(), e))?;
+
+    let mut result = Ok(());
+    for select_gp in select_gps {
+        if let Err(e) = select_gp.exec(&script) {
+            keep_first(&mut result, e);
+        }
+    }
+    result
+}
+
+pub fn handle_group_rename(args: GroupRenameArgs) -> Result<(), CliError> {
+    let gp_map = group_map(true);
+    let gp = match select_groups(&gp_map, &args.group)?.as_slice() {
+        [gp] => *gp,
+        gps => {
+            let uuids = gps.iter().map(|gp| gp.gp_info.gp_uuid.clone()).collect();
+            return Err(GroupSelectError::Ambiguous(args.group, uuids).into());
+        }
+    };
+    if !is_valid_group_name(&args.name) {
+        let err = CabinetConfigError::InvalidGroupName {
+            group_uuid: gp.gp_info.gp_uuid.clone(),
+            name: args.name,
+        };
+        return Err(CabinetError::from(err).into());
+    }
+    if let Some(other) = gp_map.values().find(|other| {
+        other.gp_info.name == args.name && other.gp_info.gp_uuid != gp.gp_info.gp_uuid
+    }) {
+        warn!(
+            "Group {} is also named {:?}",
+            other.gp_info.gp_uuid, args.name
+        );
+    }
 
-    select_gps.iter().for_each(|g| g.exec(&script));
+    let renamed = gp.rename(&args.name, args.description.as_deref())?;
+    for cab_abs_path in &renamed.renamed {
+        println!(
+            "Named group {} {:?} in {}",
+            gp.gp_info.gp_uuid,
+            args.name,
+            cab_abs_path.display()
+        );
+    }
+    let mut result = Ok(());
+    for (cab_abs_path, e) in renamed.failed {
+        println!(
+            "Failed to name group {} {:?} in {}: {}",
+            gp.gp_info.gp_uuid,
+            args.name,
+            cab_abs_path.display(),
+            e
+        );
+        keep_first(&mut result, e);
+    }
+    result
 }
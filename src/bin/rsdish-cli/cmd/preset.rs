@@ -3,6 +3,8 @@ use std::path::Path;
 use clap::{Args, Subcommand};
 use rsdish::phy::cab::{build_cabinet_from_path, write_cabinet};
 
+use crate::err::{CliError, keep_first};
+
 #[derive(Debug, Args)]
 #[command(about = "Apply chosen presets to cabinet.")]
 pub struct PresetCmd {
@@ -39,7 +41,7 @@ pub struct PresetGateArgs {
 }
 
 
-pub fn handle_preset(cmd: PresetCmd) {
+pub fn handle_preset(cmd: PresetCmd) -> Result<(), CliError> {
     match cmd.subcmd {
         PresetSubcommand::Gate(child) => handle_preset_gate(child),
         PresetSubcommand::Main(child) => handle_preset_main(child),
@@ -47,53 +49,71 @@ pub fn handle_preset(cmd: PresetCmd) {
     }
 }
 
-fn handle_preset_gate(cmd: PresetGateArgs) {
+fn handle_preset_gate(cmd: PresetGateArgs) -> Result<(), CliError> {
+    let mut result = Ok(());
     for cab_path in cmd.cabinet_paths {
         let mut cab = match build_cabinet_from_path(Path::new(&cab_path)) {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Failed to build cabinet from path {}: {}", cab_path, e);
+                keep_first(&mut result, e);
                 continue;
             }
         };
         cab.cab_info.cab_conf.to_gate();
         match write_cabinet(&cab) {
-            Ok(_) => println!("Applied Main preset to cabinet: {}", cab_path),
-            Err(e) => eprintln!("Failed to save cabinet config for {}: {}", cab_path, e),
+            Ok(_) => println!("Applied Gate preset to cabinet: {}", cab_path),
+            Err(e) => {
+                eprintln!("Failed to save cabinet config for {}: {}", cab_path, e);
+                keep_first(&mut result, e);
+            }
         }
     }
+    result
 }
 
-fn handle_preset_main(cmd: PresetMainArgs) {
+fn handle_preset_main(cmd: PresetMainArgs) -> Result<(), CliError> {
+    let mut result = Ok(());
     for cab_path in cmd.cabinet_paths {
         let mut cab = match build_cabinet_from_path(Path::new(&cab_path)) {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Failed to build cabinet from path {}: {}", cab_path, e);
+                keep_first(&mut result, e);
                 continue;
             }
         };
         cab.cab_info.cab_conf.to_main();
         match write_cabinet(&cab) {
             Ok(_) => println!("Applied Main preset to cabinet: {}", cab_path),
-            Err(e) => eprintln!("Failed to save cabinet config for {}: {}", cab_path, e),
+            Err(e) => {
+                eprintln!("Failed to save cabinet config for {}: {}", cab_path, e);
+                keep_first(&mut result, e);
+            }
         }
     }
+    result
 }
 
-fn handle_preset_mirror(cmd: PresetMirrorArgs) {
+fn handle_preset_mirror(cmd: PresetMirrorArgs) -> Result<(), CliError> {
+    let mut result = Ok(());
     for cab_path in cmd.cabinet_paths {
         let mut cab = match build_cabinet_from_path(Path::new(&cab_path)) {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Failed to build cabinet from path {}: {}", cab_path, e);
+                keep_first(&mut result, e);
                 continue;
             }
         };
         cab.cab_info.cab_conf.to_mirror();
         match write_cabinet(&cab) {
-            Ok(_) => println!("Applied Main preset to cabinet: {}", cab_path),
-            Err(e) => eprintln!("Failed to save cabinet config for {}: {}", cab_path, e),
+            Ok(_) => println!("Applied Mirror preset to cabinet: {}", cab_path),
+            Err(e) => {
+                eprintln!("Failed to save cabinet config for {}: {}", cab_path, e);
+                keep_first(&mut result, e);
+            }
         }
     }
+    result
 }
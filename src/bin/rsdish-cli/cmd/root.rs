@@ -9,7 +9,11 @@ use crate::cmd::cabinet::{CabinetCmd, handle_cabinet};
 use crate::cmd::config::{ConfigCmd, handle_config};
 use crate::cmd::group::{GroupCmd, handle_group};
 use crate::cmd::preset::{PresetCmd, handle_preset};
+use crate::cmd::status::{StatusCmd, handle_status};
 use crate::cmd::storage::{StorageCmd, handle_storage};
+use crate::cmd::trash::{TrashCmd, handle_trash};
+use crate::err::CliError;
+use crate::output::OutputFormat;
 
 // Configures Clap v3-style help menu colors
 const STYLES: Styles = Styles::styled()
@@ -23,6 +27,14 @@ const STYLES: Styles = Styles::styled()
 #[command(about = "A multi-functional sync tool for domestic storages.", long_about = None)]
 #[command(styles=STYLES)]
 pub struct RootCmd {
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Table,
+        help = "Output format of list commands"
+    )]
+    pub format: OutputFormat,
     #[command(subcommand)]
     pub subcmd: SubcommandEnum,
 }
@@ -37,14 +49,18 @@ pub enum SubcommandEnum {
     Group(GroupCmd),
     Config(ConfigCmd),
     Preset(PresetCmd),
+    Trash(TrashCmd),
+    Status(StatusCmd),
 }
 
-pub fn handle_root(cmd: RootCmd) {
+pub fn handle_root(cmd: RootCmd) -> Result<(), CliError> {
     match cmd.subcmd {
         SubcommandEnum::Cabinet(child) => handle_cabinet(child),
-        SubcommandEnum::Storage(child) => handle_storage(child),
-        SubcommandEnum::Group(child) => handle_group(child),
+        SubcommandEnum::Storage(child) => handle_storage(child, cmd.format),
+        SubcommandEnum::Group(child) => handle_group(child, cmd.format),
         SubcommandEnum::Config(child) => handle_config(child),
         SubcommandEnum::Preset(child) => handle_preset(child),
+        SubcommandEnum::Trash(child) => handle_trash(child, cmd.format),
+        SubcommandEnum::Status(child) => handle_status(child, cmd.format),
     }
 }
@@ -0,0 +1,109 @@
+use clap::Args;
+use rsdish::{
+    logi::{conflict::ConflictPolicy, status::GroupStatus, vmem::VmemOptions},
+    user::registry::registry,
+};
+
+use crate::{
+    cmd::group::{build_vmem, group_map, resolve_groups, vmem_options},
+    err::{CliError, keep_first},
+    output::{OutputFormat, format_ago, print_serialized},
+};
+
+#[derive(Debug, Args)]
+#[command(about = "Overview of every group: online members, pending work, last sync.")]
+pub struct StatusCmd {
+    #[arg(short, long, help = "Show outdated files per dst")]
+    pub verbose: bool,
+    #[arg(
+        value_name = "group(s)",
+        help = "Group uuids, uuid prefixes, names or globs, all if none"
+    )]
+    pub groups: Vec<String>,
+}
+
+pub fn handle_status(cmd: StatusCmd, format: OutputFormat) -> Result<(), CliError> {
+    let gp_map = group_map(false);
+    let select_gps = resolve_groups(&gp_map, cmd.groups.is_empty(), &cmd.groups)?;
+    let reg = registry();
+    // groups with every member offline are only known to the registry
+    let offline_gps = if cmd.groups.is_empty() {
+        reg.offline_groups(&gp_map)
+    } else {
+        vec![]
+    };
+
+    // priority-wins only reports, so status never aborts on conflicts
+    let vmem_opts = VmemOptions {
+        conflict_policy: ConflictPolicy::PriorityWins,
+        ..vmem_options(false, false)
+    };
+
+    let mut result = Ok(());
+    let mut statuses: Vec<GroupStatus> = vec![];
+    for select_gp in select_gps.into_iter().chain(&offline_gps) {
+        match build_vmem(select_gp, &vmem_opts) {
+            Ok(vmem) => statuses.push(select_gp.status(&vmem, &reg)),
+            Err(e) => keep_first(&mut result, e),
+        }
+    }
+
+    if format != OutputFormat::Table {
+        print_serialized(&statuses, format);
+        return result;
+    }
+
+    println!(
+        "{:<36}  {:<12} {:>3} {:>3} {:>4} {:>7} {:>7} {:>8} {:>5} {:>6}  LAST SYNC",
+        "GROUP", "NAME", "SRC", "DST", "LINK", "OFFLINE", "FILES", "OUTDATED", "LINKS", "CLEANS"
+    );
+    for status in &statuses {
+        println!(
+            "{:<36}  {:<12} {:>3} {:>3} {:>4} {:>7} {:>7} {:>8} {:>5} {:>6}  {}",
+            status.gp_uuid,
+            if status.name.is_empty() {
+                "-"
+            } else {
+                &status.name
+            },
+            status.srcs,
+            status.dsts,
+            status.links,
+            status.offline.len(),
+            status.files,
+            status.outdated(),
+            status.missing_links,
+            status.pending_cleans,
+            format_ago(status.last_synced),
+        );
+        if cmd.verbose {
+            for dst in &status.dst_status {
+                println!("  Dst: {:?} ({} outdated)", dst.cab_abs_path, dst.outdated);
+            }
+            for reg_mem in &status.offline {
+                println!(
+                    "  Offline: {:?} (last seen {}, last synced {})",
+                    reg_mem.cab_abs_path,
+                    format_ago(Some(reg_mem.last_seen)),
+                    format_ago(reg_mem.last_synced)
+                );
+            }
+        }
+    }
+
+    for status in &statuses {
+        if status.at_risk() {
+            println!(
+                "Warning: group {} has only {} online copy",
+                status.gp_uuid, status.copies
+            );
+        }
+        if status.conflicts > 0 {
+            println!(
+                "Warning: group {} has {} conflicts, see `rsdish group conflicts`",
+                status.gp_uuid, status.conflicts
+            );
+        }
+    }
+    result
+}
@@ -1,14 +1,24 @@
 use std::path::PathBuf;
 
 use clap::{Args, Subcommand};
+use indicatif::HumanBytes;
 use rsdish::{
     phy::{
-        dk::disks,
-        stg::{Storage, build_storages_from_paths},
+        dk::{DiskFilter, disk_infos, disks},
+        fs_caps::FsCapabilities,
+        stg::{DiscoveryOptions, Storage, StorageInfo, build_storages_from_paths},
+        stg_id::storage_identity,
     },
     user::user_conf::user_conf,
 };
 
+use tracing::error;
+
+use crate::{
+    err::{CliError, keep_first},
+    output::{OutputFormat, print_serialized},
+};
+
 #[derive(Debug, Args)]
 #[command(about = "Info of storages.")]
 pub struct StorageCmd {
@@ -19,6 +29,7 @@ pub struct StorageCmd {
 #[derive(Debug, Subcommand)]
 pub enum StorageSubcommand {
     List(StorageListArgs),
+    Init(StorageInitArgs),
 }
 
 #[derive(Debug, Args)]
@@ -28,35 +39,84 @@ pub struct StorageListArgs {
     pub verbose: bool,
 }
 
-pub fn handle_storage(cmd: StorageCmd) {
+#[derive(Debug, Args)]
+#[command(about = "Write the identity marker of storages, all scanned ones by default.")]
+pub struct StorageInitArgs {
+    pub storage_paths: Vec<PathBuf>,
+}
+
+pub fn handle_storage(cmd: StorageCmd, format: OutputFormat) -> Result<(), CliError> {
     match cmd.subcmd {
-        StorageSubcommand::List(child) => handle_storage_list(child),
+        StorageSubcommand::List(child) => handle_storage_list(child, format),
+        StorageSubcommand::Init(child) => handle_storage_init(child),
     }
 }
 
-pub fn storages() -> Vec<Storage> {
-    let dks = disks();
-    let custom_stg_paths = user_conf().custom_storages;
+// write_ids creates missing storage markers, for commands that write anyway
+pub fn storages(write_ids: bool) -> Vec<Storage> {
+    let user_conf = user_conf();
+    let dk_filter = DiskFilter::new(
+        &user_conf.mount_includes,
+        &user_conf.mount_excludes,
+        &user_conf.fs_type_includes,
+        &user_conf.fs_type_excludes,
+        user_conf.removable,
+    )
+    .unwrap_or_else(|e| {
+        error!(
+            "Invalid mount_includes or mount_excludes: {}. Using none.",
+            e
+        );
+        DiskFilter {
+            fs_type_includes: user_conf.fs_type_includes.clone(),
+            fs_type_excludes: user_conf.fs_type_excludes.clone(),
+            removable: user_conf.removable,
+            ..Default::default()
+        }
+    });
+    // custom storages are always scanned
+    let dk_infos = disk_infos();
+    let dks = disks(&dk_infos, &dk_filter);
+    let disc_opts = DiscoveryOptions::new(user_conf.discovery_depth, &user_conf.discovery_excludes)
+        .unwrap_or_else(|e| {
+            error!("Invalid discovery_excludes: {}. Using none.", e);
+            DiscoveryOptions {
+                depth: user_conf.discovery_depth,
+                ..Default::default()
+            }
+        });
+    let disc_opts = DiscoveryOptions {
+        write_ids,
+        ..disc_opts
+    };
 
     let mut stg_paths = dks;
-    stg_paths.extend(custom_stg_paths.iter().map(|s| PathBuf::from(s)));
+    stg_paths.extend(user_conf.custom_storages.iter().map(PathBuf::from));
 
-    let stgs = build_storages_from_paths(&stg_paths.iter().map(|s| s.as_path()).collect());
-    stgs
+    build_storages_from_paths(
+        &stg_paths.iter().map(|s| s.as_path()).collect(),
+        &dk_infos,
+        &disc_opts,
+    )
 }
 
-pub fn handle_storage_list(args: StorageListArgs) {
-    let stgs = storages();
+pub fn handle_storage_list(args: StorageListArgs, format: OutputFormat) -> Result<(), CliError> {
+    let stgs = storages(false);
+
+    if format != OutputFormat::Table {
+        print_serialized(&stgs, format);
+        return Ok(());
+    }
 
     if !args.verbose {
         // TODO: print like storage tree, storage only print storage_abs_path, cabinet only print groupuuids
         for stg in stgs.iter() {
-            println!("Storage: \"{}\"", stg.stg_info.abs_path.display());
+            println!("Storage: \"{}\"{}", stg.stg_info.abs_path.display(), storage_label(&stg.stg_info));
 
             let cabs = &stg.cabs;
             for (j, cab) in cabs.iter().enumerate() {
                 let cab_prefix = if j == cabs.len() - 1 { "└──" } else { "├──" };
-                let cab_rel_path = cab.cab_info.abs_path.strip_prefix(stg.stg_info.abs_path.as_path()).unwrap();
+                let cab_rel_path = cab.cab_info.abs_path.strip_prefix(stg.stg_info.abs_path.as_path()).unwrap_or(&cab.cab_info.abs_path);
                 let group_uuids: Vec<String> = cab
                     .cab_info
                     .cab_conf
@@ -67,10 +127,110 @@ pub fn handle_storage_list(args: StorageListArgs) {
                 println!("{} Cabinet: {:?}, Memberships: {:?}", cab_prefix, cab_rel_path, group_uuids);
             }
         }
-        return;
+        return Ok(());
     }
 
-    for stg in stgs {
-        println!("{:#?}", stg);
+    for stg in stgs.iter() {
+        let identity = &stg.stg_info.identity;
+        println!("Storage: \"{}\"", stg.stg_info.abs_path.display());
+        println!("  id: {}", stg.stg_info.stg_id());
+        println!("  fs: {}", format_caps(&stg.stg_info.caps));
+        for (key, value) in [
+            ("fs uuid", &identity.fs_uuid),
+            ("label", &identity.label),
+            ("serial", &identity.serial),
+        ] {
+            if !value.is_empty() {
+                println!("  {}: {}", key, value);
+            }
+        }
+        for cab in &stg.cabs {
+            println!("Cabinet: {:?}", cab.cab_info.abs_path);
+            match cab.cab_info.cab_conf.cabinet_uuid.as_str() {
+                "" => println!("  uuid: none, assigned on the next sync"),
+                uuid => println!("  uuid: {}", uuid),
+            }
+            for mem_conf in &cab.cab_info.cab_conf.memberships {
+                println!(
+                    "  Membership: {} (priority: {}, src: {}, dst: {}, link: {})",
+                    mem_conf.group_uuid,
+                    mem_conf.priority,
+                    mem_conf.src_option.enable,
+                    mem_conf.dst_option.enable,
+                    mem_conf.link_option.enable
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_storage_init(args: StorageInitArgs) -> Result<(), CliError> {
+    let stg_paths: Vec<PathBuf> = if args.storage_paths.is_empty() {
+        storages(false)
+            .into_iter()
+            .map(|stg| stg.stg_info.abs_path)
+            .collect()
+    } else {
+        args.storage_paths
+    };
+
+    let mut result = Ok(());
+    for stg_path in stg_paths {
+        let stg_abs_path = match stg_path.canonicalize() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to read storage {}: {}", stg_path.display(), e);
+                keep_first(&mut result, e);
+                continue;
+            }
+        };
+        let identity = storage_identity(&stg_abs_path, true);
+        println!("{}: {}", stg_abs_path.display(), identity.id());
+    }
+    result
+}
+
+// ex: ` (label: Backup)`, empty for unlabelled storages
+fn storage_label(stg_info: &StorageInfo) -> String {
+    match stg_info.identity.label.as_str() {
+        "" => String::new(),
+        label => format!(" (label: {})", label),
+    }
+}
+
+// ex: `vfat (max file size 4.00 GiB, no symlinks, case-insensitive, mtime granularity 2s, invalid chars: "*:<>?\|)`
+fn format_caps(caps: &FsCapabilities) -> String {
+    let mut notes: Vec<String> = vec![];
+    if let Some(max) = caps.max_file_size {
+        notes.push(format!("max file size {}", HumanBytes(max)));
+    }
+    if !caps.symlinks {
+        notes.push("no symlinks".to_string());
+    }
+    if !caps.case_sensitive {
+        notes.push("case-insensitive".to_string());
+    }
+    if !caps.mtime_granularity.is_zero() {
+        notes.push(format!(
+            "mtime granularity {}",
+            humantime::format_duration(caps.mtime_granularity)
+        ));
+    }
+    if !caps.invalid_chars.is_empty() {
+        notes.push(format!(
+            "invalid chars: {}",
+            String::from_iter(&caps.invalid_chars)
+        ));
+    }
+    let fs_type = if caps.fs_type.is_empty() {
+        "unknown"
+    } else {
+        &caps.fs_type
+    };
+    if notes.is_empty() {
+        fs_type.to_string()
+    } else {
+        format!("{} ({})", fs_type, notes.join(", "))
     }
 }
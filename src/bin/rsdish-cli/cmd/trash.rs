@@ -0,0 +1,180 @@
+use std::{env, path::PathBuf};
+
+use clap::{Args, Subcommand};
+use serde::Serialize;
+
+use rsdish::phy::{
+    cab::build_cabinet_from_path,
+    trash::{TrashEntry, find_trash, list_trash, prune_trash, restore_trash},
+};
+
+use crate::{
+    err::{CliError, keep_first},
+    output::{OutputFormat, print_serialized},
+};
+
+#[derive(Debug, Args)]
+#[command(about = "Overwritten, cleaned and quarantined files of a cabinet.")]
+pub struct TrashCmd {
+    #[command(subcommand)]
+    pub subcmd: TrashSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TrashSubcommand {
+    List(TrashListArgs),
+    Restore(TrashRestoreArgs),
+    Prune(TrashPruneArgs),
+}
+
+#[derive(Debug, Args)]
+#[command(about = "List trash entries of given cabinets, or current cabinet.")]
+pub struct TrashListArgs {
+    #[arg(value_name = "cabinet_path(s)")]
+    pub cabinet_paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Restore trash entries to their original path.")]
+pub struct TrashRestoreArgs {
+    #[arg(short, long, help = "Cabinet path, default current dir")]
+    pub cabinet: Option<PathBuf>,
+    #[arg(short, long, help = "Trash the file occupying the original path")]
+    pub force: bool,
+    #[arg(
+        value_name = "id(s)",
+        required = true,
+        help = "Trash id or unique id prefix"
+    )]
+    pub ids: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Drop trash entries beyond the cabinet retention policy.")]
+pub struct TrashPruneArgs {
+    #[arg(value_name = "cabinet_path(s)")]
+    pub cabinet_paths: Vec<PathBuf>,
+}
+
+pub fn handle_trash(cmd: TrashCmd, format: OutputFormat) -> Result<(), CliError> {
+    match cmd.subcmd {
+        TrashSubcommand::List(child) => handle_trash_list(child, format),
+        TrashSubcommand::Restore(child) => handle_trash_restore(child),
+        TrashSubcommand::Prune(child) => handle_trash_prune(child),
+    }
+}
+
+fn cabinet_paths_or_current(cabinet_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, CliError> {
+    if cabinet_paths.is_empty() {
+        return Ok(vec![env::current_dir()?]);
+    }
+    Ok(cabinet_paths)
+}
+
+#[derive(Serialize)]
+struct CabinetTrash {
+    cab_abs_path: PathBuf,
+    entries: Vec<TrashEntry>,
+}
+
+pub fn handle_trash_list(args: TrashListArgs, format: OutputFormat) -> Result<(), CliError> {
+    let mut result = Ok(());
+    let mut cab_trashes: Vec<CabinetTrash> = Vec::new();
+    for cab_path in cabinet_paths_or_current(args.cabinet_paths)? {
+        let entries = match list_trash(&cab_path) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Failed to list trash of {}: {}", cab_path.display(), e);
+                keep_first(&mut result, e);
+                continue;
+            }
+        };
+        cab_trashes.push(CabinetTrash {
+            cab_abs_path: cab_path,
+            entries,
+        });
+    }
+
+    if format != OutputFormat::Table {
+        print_serialized(&cab_trashes, format);
+        return result;
+    }
+
+    for CabinetTrash {
+        cab_abs_path: cab_path,
+        entries,
+    } in cab_trashes
+    {
+        println!("Cabinet: {:?}", cab_path);
+        for (i, entry) in entries.iter().enumerate() {
+            let prefix = if i == entries.len() - 1 {
+                "└──"
+            } else {
+                "├──"
+            };
+            println!(
+                "{} {} {} {:<9} {:>10} {:?} (group {})",
+                prefix,
+                entry.meta.id,
+                humantime::format_rfc3339_seconds(entry.meta.trashed_at),
+                entry.meta.reason,
+                entry.meta.size,
+                entry.meta.file_rel_path,
+                entry.meta.gp_uuid
+            );
+        }
+    }
+    result
+}
+
+pub fn handle_trash_restore(args: TrashRestoreArgs) -> Result<(), CliError> {
+    let cab_path = match args.cabinet {
+        Some(t) => t,
+        None => env::current_dir()?,
+    };
+
+    let mut result = Ok(());
+    for id in &args.ids {
+        let restored = find_trash(&cab_path, id)
+            .and_then(|entry| restore_trash(&cab_path, &entry, args.force));
+        match restored {
+            Ok(path) => println!("Restored {} to {}", id, path.display()),
+            Err(e) => {
+                eprintln!("Failed to restore {}: {}", id, e);
+                keep_first(&mut result, e);
+            }
+        }
+    }
+    result
+}
+
+pub fn handle_trash_prune(args: TrashPruneArgs) -> Result<(), CliError> {
+    let mut result = Ok(());
+    for cab_path in cabinet_paths_or_current(args.cabinet_paths)? {
+        let cab = match build_cabinet_from_path(&cab_path) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "Failed to build cabinet from path {}: {}",
+                    cab_path.display(),
+                    e
+                );
+                keep_first(&mut result, e);
+                continue;
+            }
+        };
+
+        match prune_trash(&cab_path, &cab.cab_info.cab_conf.trash) {
+            Ok(pruned) => println!(
+                "Pruned {} trash entries of {}",
+                pruned.len(),
+                cab_path.display()
+            ),
+            Err(e) => {
+                eprintln!("Failed to prune trash of {}: {}", cab_path.display(), e);
+                keep_first(&mut result, e);
+            }
+        }
+    }
+    result
+}
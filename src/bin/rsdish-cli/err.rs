@@ -0,0 +1,68 @@
+use std::{io, path::PathBuf};
+
+use confy::ConfyError;
+use rsdish::{
+    logi::{
+        conflict::ConflictError, exec::ExecError, gp::GroupSelectError, report::Operation,
+        sync::SyncError,
+    },
+    phy::{cab::CabinetError, trash::TrashError},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Config(#[from] ConfyError),
+
+    #[error(transparent)]
+    Cabinet(#[from] CabinetError),
+
+    #[error(transparent)]
+    Trash(#[from] TrashError),
+
+    #[error("Failed to read script {0}: {1}")]
+    Script(PathBuf, io::Error),
+
+    #[error(transparent)]
+    Select(#[from] GroupSelectError),
+
+    #[error(transparent)]
+    Conflict(#[from] ConflictError),
+
+    #[error("{1} file(s) failed to {0}")]
+    Failed(Operation, u64),
+
+    #[error(transparent)]
+    Exec(#[from] ExecError),
+
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+}
+
+impl CliError {
+    // 1: io, 2 is taken by clap for usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Io(_) | CliError::Script(..) => 1,
+            CliError::Config(_) | CliError::Cabinet(_) => 3,
+            CliError::Trash(_) => 4,
+            CliError::Select(_) => 5,
+            CliError::Conflict(_) => 6,
+            CliError::Failed(..) => 7,
+            CliError::Exec(_) => 8,
+            CliError::Sync(_) => 9,
+        }
+    }
+}
+
+// For commands going over several items: every item is tried,
+// the first failure is kept as the command result.
+pub fn keep_first(result: &mut Result<(), CliError>, e: impl Into<CliError>) {
+    if result.is_ok() {
+        *result = Err(e.into());
+    }
+}
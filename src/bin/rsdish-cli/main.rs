@@ -1,4 +1,7 @@
 mod cmd;
+mod err;
+mod output;
+mod progress;
 
 use clap::Parser;
 use cmd::root::RootCmd;
@@ -9,11 +12,15 @@ use crate::cmd::root::handle_root;
 fn main() {
     let subscriber = FmtSubscriber::builder()
         .with_max_level(tracing::Level::INFO)
+        // keep stdout clean for --format json/yaml
+        .with_writer(std::io::stderr)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global subscriber");
 
     let cli = RootCmd::parse();
-    println!("{:#?}", cli);
-    handle_root(cli);
+    if let Err(e) = handle_root(cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
 }
@@ -0,0 +1,49 @@
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use indicatif::HumanDuration;
+use rsdish::user::registry::RegistryMember;
+use serde::Serialize;
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    // human readable trees
+    #[default]
+    Table,
+    Json,
+    Yaml,
+}
+
+// Print value as json or yaml, table output is up to each command.
+pub fn print_serialized<T: Serialize + ?Sized>(value: &T, format: OutputFormat) {
+    let serialized = match format {
+        OutputFormat::Table => return,
+        OutputFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        OutputFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+    };
+
+    match serialized {
+        Ok(s) => println!("{}", s.trim_end()),
+        Err(e) => error!("Failed to serialize output: {}", e),
+    }
+}
+
+// ex: 5 minutes ago
+pub fn format_ago(t: Option<SystemTime>) -> String {
+    match t {
+        Some(t) => match t.elapsed() {
+            Ok(elapsed) => format!("{} ago", HumanDuration(elapsed)),
+            Err(_) => humantime::format_rfc3339_seconds(t).to_string(),
+        },
+        None => "never".to_string(),
+    }
+}
+
+// ex: offline on "Backup", so the user knows which drive to plug in
+pub fn format_offline(reg_mem: &RegistryMember) -> String {
+    match reg_mem.stg_label.as_str() {
+        "" => "offline".to_string(),
+        label => format!("offline on {:?}", label),
+    }
+}
@@ -0,0 +1,53 @@
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use rsdish::logi::progress::{SyncObserver, SyncProgress, SyncSummary, SyncTotals};
+
+// Renders sync progress as a bar on stderr, hidden when it is not a terminal.
+pub struct BarObserver {
+    bar: ProgressBar,
+}
+
+impl BarObserver {
+    pub fn new() -> Self {
+        let bar = ProgressBar::new(0);
+        if let Ok(style) = ProgressStyle::with_template(
+            "{bar:30} {bytes}/{total_bytes} {bytes_per_sec} eta {eta} {msg}",
+        ) {
+            bar.set_style(style);
+        }
+        BarObserver { bar }
+    }
+}
+
+impl SyncObserver for BarObserver {
+    fn on_start(&self, totals: &SyncTotals) {
+        self.bar.set_length(totals.bytes);
+        self.bar.reset();
+    }
+
+    fn on_progress(&self, progress: &SyncProgress) {
+        self.bar.set_position(progress.bytes_done);
+        self.bar.set_message(format!(
+            "[{}/{}] {}",
+            progress.files_done,
+            progress.files_total,
+            progress.current_file.display()
+        ));
+    }
+
+    fn on_finish(&self, summary: &SyncSummary) {
+        self.bar.finish_and_clear();
+        print_sync_summary(summary);
+    }
+}
+
+fn print_sync_summary(summary: &SyncSummary) {
+    println!(
+        "Copied: {}, deleted: {}, skipped: {}, failed: {}, {} in {}",
+        summary.copied,
+        summary.deleted,
+        summary.skipped,
+        summary.failed,
+        HumanBytes(summary.bytes),
+        HumanDuration(summary.elapsed)
+    );
+}
@@ -1,7 +1,14 @@
 pub mod mem;
 pub mod gp;
 pub mod vmem;
+pub mod conflict;
 pub mod sync;
+pub mod plan;
+pub mod progress;
+pub mod report;
+pub mod space;
+pub mod status;
+pub mod manifest;
 pub mod link;
 pub mod exec;
 pub mod clean;
\ No newline at end of file
@@ -1,36 +1,84 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 use ignore::{DirEntry, WalkBuilder};
+use thiserror::Error;
 use tracing::{error, info};
 
-use crate::logi::{gp::Group, mem::Member, vmem::VirtualMember};
+use crate::{
+    logi::{
+        gp::Group,
+        mem::Member,
+        report::{Operation, Report, ReportAction, ReportEntry},
+        vmem::VirtualMember,
+    },
+    phy::{
+        cab::is_reserved_name,
+        trash::{TrashError, TrashReason, trash_file},
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum CleanError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Trash(#[from] TrashError),
+}
 
 impl Group {
-    pub fn clean_from_vmem(&self, vmem: &VirtualMember) {
+    pub fn clean_from_vmem(&self, vmem: &VirtualMember) -> Report {
         let dsts = self
             .mems
             .iter()
             .filter(|mem| mem.mem_info.mem_conf.dst_option.enable);
+
+        let mut report = Report::new(Operation::Clean, &self.gp_info.gp_uuid);
         dsts.for_each(|dst| {
             info!("run clean for dst {:?}", dst.mem_info.cab_info.abs_path);
-            dst.clean_from_vmem(vmem);
+            dst.clean_from_vmem(vmem, &mut report);
         });
+        report
     }
 }
 
 impl Member {
-    fn clean_from_vmem(&self, vmem: &VirtualMember) {
+    fn clean_from_vmem(&self, vmem: &VirtualMember, report: &mut Report) {
+        let dst_root = self.mem_info.cab_info.abs_path.as_path();
+
+        for rel_path in self.clean_candidates(vmem) {
+            let path = dst_root.join(&rel_path);
+            let entry = ReportEntry::new(ReportAction::Clean, path.clone())
+                .storage(&self.mem_info.stg_info)
+                .reason("src priority is not the highest");
+            match self.clean_file(&rel_path) {
+                Ok(_) => {
+                    info!("Removed low-priority file: {}", path.display());
+                    report.push(entry);
+                }
+                Err(e) => {
+                    error!("Failed to remove {}: {}", path.display(), e);
+                    report.push(entry.failed(e));
+                }
+            }
+        }
+    }
+
+    // Files under this dst that clean would remove, relative to its root.
+    pub fn clean_candidates(&self, vmem: &VirtualMember) -> Vec<PathBuf> {
         let dst_root = self.mem_info.cab_info.abs_path.as_path();
 
         let walker = WalkBuilder::new(dst_root)
             .add_custom_ignore_filename(env!("SRC_IGNORE_NAME"))
             .filter_entry(|ent: &DirEntry| {
-                // default: ignore cabinet config
-                !ent.file_name()
-                    .to_string_lossy()
-                    .eq(env!("CABINET_CONFIG_NAME"))
+                // default: ignore cabinet config and rsdish files
+                !is_reserved_name(ent.file_name())
             })
             .build();
+        let filter = self.file_filter();
         let mut clean_queue: Vec<PathBuf> = Vec::new();
 
         for ent in walker {
@@ -76,24 +124,35 @@ impl Member {
             };
 
             let Some(exist_leaf) = vmem.virtual_tree.get(file_rel_path) else {
-                return;
+                return clean_queue;
             };
 
+            // not held by this membership
+            if !filter.matches(file_rel_path, ent_meta.len()) {
+                continue;
+            }
+
             // clean if current is not highest in the tree
-            if exist_leaf.priority < vmem.highest_priority {
-                clean_queue.push(file_abs_path.clone());
+            if vmem.plan_priority(exist_leaf) < vmem.highest_priority {
+                clean_queue.push(file_rel_path.to_path_buf());
             }
         }
 
-        for path in clean_queue {
-            match fs::remove_file(&path) {
-                Ok(_) => {
-                    info!("Removed low-priority file: {}", path.display());
-                }
-                Err(e) => {
-                    error!("Failed to remove {}: {}", path.display(), e);
-                }
-            }
+        clean_queue
+    }
+
+    fn clean_file(&self, file_rel_path: &Path) -> Result<(), CleanError> {
+        let dst_root = self.mem_info.cab_info.abs_path.as_path();
+        if self.mem_info.cab_info.cab_conf.trash.enable {
+            trash_file(
+                dst_root,
+                &self.mem_info.mem_conf.group_uuid,
+                file_rel_path,
+                TrashReason::Clean,
+            )?;
+        } else {
+            fs::remove_file(dst_root.join(file_rel_path))?;
         }
+        Ok(())
     }
 }
@@ -0,0 +1,561 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::logi::vmem::{VirtualLeaf, VirtualMember, VmemOptions};
+
+#[derive(Debug, Error)]
+pub enum ConflictError {
+    #[error("{} conflicting path(s) found, aborted", .0.len())]
+    Abort(Vec<Conflict>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // the highest priority version is kept, as without detection
+    #[default]
+    PriorityWins,
+    // the most recently modified version is kept
+    NewestWins,
+    // the highest priority version is kept, the others are saved beside it
+    KeepBoth,
+    // refuse to build the virtual member
+    Abort,
+}
+
+// Keys deciding between leaves of equal priority, tried in order.
+// The cabinet id is always tried last, so the result never depends on
+// the order srcs were found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TieBreak {
+    // later mtime wins
+    Newest,
+    // earlier mtime wins
+    Oldest,
+    // larger size wins
+    Largest,
+    // smaller size wins
+    Smallest,
+    // smaller cabinet id wins
+    Cabinet,
+}
+
+pub fn default_tie_break() -> Vec<TieBreak> {
+    vec![TieBreak::Newest, TieBreak::Largest, TieBreak::Cabinet]
+}
+
+// Why the version at a path won, against the closest other version.
+// Ordered by how close that version came.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WinReason {
+    // no other src holds the path
+    Only,
+    // higher priority
+    Priority,
+    // equal priority, decided by the tie-break key at this position
+    TieBreak(usize, TieBreak),
+    // newest-wins took it over a higher priority version
+    NewestWins,
+    // another version saved beside the winner by keep-both
+    KeepBoth,
+}
+
+// Divergent versions of one path across srcs.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub file_rel_path: PathBuf,
+    // winning order, highest priority first
+    pub versions: Vec<VirtualLeaf>,
+    // src path of the version kept at file_rel_path
+    pub winner_abs_path: PathBuf,
+    // paths the other versions are saved at with keep-both
+    pub kept_rel_paths: Vec<PathBuf>,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ConflictPolicy::PriorityWins => "priority-wins",
+            ConflictPolicy::NewestWins => "newest-wins",
+            ConflictPolicy::KeepBoth => "keep-both",
+            ConflictPolicy::Abort => "abort",
+        };
+        f.pad(s)
+    }
+}
+
+impl fmt::Display for TieBreak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TieBreak::Newest => "newest",
+            TieBreak::Oldest => "oldest",
+            TieBreak::Largest => "largest",
+            TieBreak::Smallest => "smallest",
+            TieBreak::Cabinet => "cabinet",
+        };
+        f.pad(s)
+    }
+}
+
+impl fmt::Display for WinReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WinReason::Only => write!(f, "only src"),
+            WinReason::Priority => write!(f, "highest priority"),
+            WinReason::TieBreak(_, key) => write!(f, "equal priority, {}", key),
+            WinReason::NewestWins => write!(f, "newest-wins"),
+            WinReason::KeepBoth => write!(f, "keep-both"),
+        }
+    }
+}
+
+impl VirtualLeaf {
+    // Whether two leaves hold different content. Hashes decide when both
+    // are known, the size otherwise: copies made without keeping mtime, or
+    // on a coarser filesystem, differ in mtime alone.
+    pub fn diverges(&self, other: &VirtualLeaf) -> bool {
+        if self.size != other.size {
+            return true;
+        }
+        match (&self.hash, &other.hash) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+}
+
+fn compare_by(key: TieBreak, a: &VirtualLeaf, b: &VirtualLeaf) -> Ordering {
+    match key {
+        TieBreak::Newest => a.mtime.cmp(&b.mtime),
+        TieBreak::Oldest => b.mtime.cmp(&a.mtime),
+        TieBreak::Largest => a.size.cmp(&b.size),
+        TieBreak::Smallest => b.size.cmp(&a.size),
+        TieBreak::Cabinet => b.cab_id.cmp(&a.cab_id),
+    }
+}
+
+// Greater means a wins over b.
+pub fn compare_leaves(a: &VirtualLeaf, b: &VirtualLeaf, tie_break: &[TieBreak]) -> Ordering {
+    a.priority
+        .cmp(&b.priority)
+        .then_with(|| {
+            tie_break
+                .iter()
+                .map(|key| compare_by(*key, a, b))
+                .find(|o| o.is_ne())
+                .unwrap_or(Ordering::Equal)
+        })
+        .then_with(|| compare_by(TieBreak::Cabinet, a, b))
+}
+
+// The key winner wins over loser by, see compare_leaves.
+pub fn win_reason(winner: &VirtualLeaf, loser: &VirtualLeaf, tie_break: &[TieBreak]) -> WinReason {
+    if winner.priority != loser.priority {
+        return WinReason::Priority;
+    }
+    tie_break
+        .iter()
+        .chain([&TieBreak::Cabinet])
+        .enumerate()
+        .find(|(_, key)| compare_by(**key, winner, loser).is_ne())
+        .map_or(
+            WinReason::TieBreak(tie_break.len(), TieBreak::Cabinet),
+            |(i, key)| WinReason::TieBreak(i, *key),
+        )
+}
+
+// ex: photos/a.jpg -> photos/a.conflict-1700000000.jpg, then
+// photos/a.conflict-1700000000-2.jpg for the second version of that second
+fn conflict_rel_path(file_rel_path: &Path, mtime: SystemTime, n: usize) -> PathBuf {
+    let secs = mtime
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let tag = match n {
+        1 => format!("conflict-{}", secs),
+        n => format!("conflict-{}-{}", secs, n),
+    };
+    let stem = file_rel_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = match file_rel_path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, tag, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, tag),
+    };
+    file_rel_path.with_file_name(name)
+}
+
+impl VirtualMember {
+    // Judge the versions collected while learning srcs.
+    // A lower priority version only conflicts when it is newer than the
+    // winner, an older one is just a stale copy. Same priority versions
+    // always conflict, since neither is authoritative.
+    pub fn resolve_conflicts(&mut self, vmem_opts: &VmemOptions) -> Result<(), ConflictError> {
+        let policy = vmem_opts.conflict_policy;
+        let versions_map = std::mem::take(&mut self.versions);
+
+        for (path, mut versions) in versions_map {
+            let Some(winner) = self.virtual_tree.get(&path).cloned() else {
+                continue;
+            };
+            let is_conflict = versions
+                .iter()
+                .filter(|v| winner.diverges(v))
+                .any(|v| v.priority == winner.priority || v.mtime > winner.mtime);
+            if !is_conflict {
+                continue;
+            }
+
+            versions.sort_by(|a, b| compare_leaves(b, a, &vmem_opts.tie_break));
+            let mut conflict = Conflict {
+                file_rel_path: path.clone(),
+                versions,
+                winner_abs_path: winner.file_abs_path.clone(),
+                kept_rel_paths: vec![],
+            };
+
+            match policy {
+                ConflictPolicy::PriorityWins | ConflictPolicy::Abort => {}
+                ConflictPolicy::NewestWins => {
+                    // ties keep the priority order
+                    let newest = conflict
+                        .versions
+                        .iter()
+                        .reduce(|a, b| if b.mtime > a.mtime { b } else { a })
+                        .cloned()
+                        .unwrap_or_else(|| winner.clone());
+                    conflict.winner_abs_path = newest.file_abs_path.clone();
+                    // keeps its own priority, only planned above the winner
+                    if newest.file_abs_path != winner.file_abs_path {
+                        self.promoted.insert(path.clone(), winner.priority);
+                        self.resolved.insert(path.clone(), WinReason::NewestWins);
+                        self.virtual_tree.insert(path.clone(), newest);
+                    }
+                }
+                ConflictPolicy::KeepBoth => {
+                    for version in &conflict.versions {
+                        if !winner.diverges(version) {
+                            continue;
+                        }
+                        // a src may hold it from an earlier sync already,
+                        // another version of the same second gets the next name
+                        let Some(kept_rel_path) = (1..)
+                            .map(|n| conflict_rel_path(&path, version.mtime, n))
+                            .find(|p| {
+                                self.virtual_tree
+                                    .get(p)
+                                    .is_none_or(|held| !held.diverges(version))
+                            })
+                        else {
+                            continue;
+                        };
+                        if self.virtual_tree.contains_key(&kept_rel_path) {
+                            continue;
+                        }
+                        // saved alongside the winner, so it reaches the same dsts
+                        let kept = VirtualLeaf {
+                            file_rel_path: kept_rel_path.clone(),
+                            priority: winner.priority,
+                            ..version.clone()
+                        };
+                        self.virtual_tree.insert(kept_rel_path.clone(), kept);
+                        self.resolved
+                            .insert(kept_rel_path.clone(), WinReason::KeepBoth);
+                        conflict.kept_rel_paths.push(kept_rel_path);
+                    }
+                }
+            }
+
+            warn!(
+                "Conflict on {} across {} srcs, keep {}",
+                path.display(),
+                conflict.versions.len(),
+                conflict.winner_abs_path.display()
+            );
+            self.conflicts.push(conflict);
+        }
+
+        if policy == ConflictPolicy::Abort && !self.conflicts.is_empty() {
+            return Err(ConflictError::Abort(std::mem::take(&mut self.conflicts)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logi::{
+            gp::{Group, GroupInfo},
+            mem::{Member, MemberInfo},
+            plan::{SyncAction, SyncReason},
+            progress::NoopObserver,
+            sync::SyncOptions,
+            vmem::build_virtual_member_from_group,
+        },
+        phy::{
+            cab::CabinetInfo,
+            cab_conf::{CoverLevel, DeleteLevel, default_cabinet_config, default_membership},
+            stg::StorageInfo,
+        },
+    };
+    use std::{fs, time::Duration};
+    use tempfile::tempdir;
+
+    fn leaf(src: &str, priority: i32, size: u64, secs: u64) -> VirtualLeaf {
+        VirtualLeaf {
+            file_rel_path: PathBuf::from("d/a.txt"),
+            file_abs_path: PathBuf::from(src).join("d/a.txt"),
+            priority,
+            size,
+            mtime: UNIX_EPOCH + Duration::from_secs(secs),
+            hash: None,
+            cab_id: src.to_string(),
+        }
+    }
+
+    fn vmem_of(leaves: &[VirtualLeaf]) -> VirtualMember {
+        let mut vmem = VirtualMember::default();
+        leaves
+            .iter()
+            .for_each(|l| vmem.learn_from_leaf(l, &default_tie_break()));
+        vmem
+    }
+
+    fn opts(conflict_policy: ConflictPolicy) -> VmemOptions {
+        VmemOptions {
+            conflict_policy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_stale_lower_priority_is_not_conflict() {
+        let mut vmem = vmem_of(&[leaf("/main", 3, 4, 200), leaf("/mirror", 1, 3, 100)]);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::Abort))
+            .unwrap();
+        assert!(vmem.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_conflicts() {
+        let leaves = [leaf("/main", 3, 4, 100), leaf("/laptop", 1, 5, 200)];
+
+        let mut vmem = vmem_of(&leaves);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::PriorityWins))
+            .unwrap();
+        assert_eq!(vmem.conflicts.len(), 1);
+        assert_eq!(
+            vmem.virtual_tree[Path::new("d/a.txt")].file_abs_path,
+            Path::new("/main/d/a.txt")
+        );
+
+        let mut vmem = vmem_of(&leaves);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::NewestWins))
+            .unwrap();
+        let newest = &vmem.virtual_tree[Path::new("d/a.txt")];
+        assert_eq!(newest.file_abs_path, Path::new("/laptop/d/a.txt"));
+        assert_eq!(newest.priority, 1);
+        assert!(vmem.plan_priority(newest) > 3);
+        assert_eq!(
+            vmem.resolution(Path::new("d/a.txt")).unwrap().1,
+            WinReason::NewestWins
+        );
+
+        let mut vmem = vmem_of(&leaves);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::KeepBoth))
+            .unwrap();
+        let kept = &vmem.virtual_tree[Path::new("d/a.conflict-200.txt")];
+        assert_eq!(kept.file_abs_path, Path::new("/laptop/d/a.txt"));
+        assert_eq!(kept.priority, 3);
+
+        // two versions from the same second keep both
+        let mut vmem = vmem_of(&[
+            leaf("/main", 3, 4, 200),
+            leaf("/laptop", 3, 5, 200),
+            leaf("/phone", 3, 6, 200),
+        ]);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::KeepBoth))
+            .unwrap();
+        assert_eq!(vmem.conflicts[0].kept_rel_paths.len(), 2);
+        assert!(
+            vmem.virtual_tree
+                .contains_key(Path::new("d/a.conflict-200-2.txt"))
+        );
+
+        let mut vmem = vmem_of(&leaves);
+        assert!(matches!(
+            vmem.resolve_conflicts(&opts(ConflictPolicy::Abort)),
+            Err(ConflictError::Abort(c)) if c.len() == 1
+        ));
+    }
+
+    fn member_in(dir: &Path, priority: i32) -> Member {
+        let mut mem_conf = default_membership();
+        mem_conf.priority = priority;
+        mem_conf.src_option.enable = true;
+        mem_conf.dst_option.enable = true;
+        mem_conf.dst_option.cover_level = CoverLevel::HigherCover;
+        Member {
+            mem_info: MemberInfo {
+                mem_conf,
+                cab_info: CabinetInfo {
+                    abs_path: dir.to_path_buf(),
+                    conf_abs_path: dir.join(env!("CABINET_CONFIG_NAME")),
+                    cab_conf: default_cabinet_config(),
+                },
+                stg_info: StorageInfo {
+                    abs_path: dir.to_path_buf(),
+                    identity: Default::default(),
+                    dev: None,
+                    available_space: None,
+                    caps: Default::default(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_newest_wins_plan() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let mems: Vec<Member> = dirs
+            .iter()
+            .zip([3, 1])
+            .map(|(dir, priority)| member_in(dir.path(), priority))
+            .collect();
+        // the lower priority laptop holds the newer edit
+        let leaves: Vec<VirtualLeaf> = dirs
+            .iter()
+            .zip([("main", 3, 100), ("edited", 1, 200)])
+            .map(|(dir, (content, priority, secs))| {
+                let file_abs_path = dir.path().join("a.txt");
+                fs::write(&file_abs_path, content).unwrap();
+                VirtualLeaf {
+                    file_rel_path: PathBuf::from("a.txt"),
+                    file_abs_path,
+                    priority,
+                    size: content.len() as u64,
+                    mtime: UNIX_EPOCH + Duration::from_secs(secs),
+                    hash: None,
+                    cab_id: dir.path().to_string_lossy().into_owned(),
+                }
+            })
+            .collect();
+        let mut vmem = vmem_of(&leaves);
+        vmem.resolve_conflicts(&opts(ConflictPolicy::NewestWins))
+            .unwrap();
+
+        let gp = Group {
+            gp_info: GroupInfo::default(),
+            mems,
+        };
+        let gp_plan = gp.plan_sync_from_vmem(&vmem, &SyncOptions::default());
+        let step_of = |dst: usize| {
+            let step = &gp_plan.mem_plans[dst].steps[0];
+            (step.action, step.reason.clone())
+        };
+        // main takes the edit, the laptop already has it
+        assert_eq!(step_of(0), (SyncAction::Overwrite, SyncReason::Outdated));
+        assert_eq!(step_of(1), (SyncAction::Skip, SyncReason::UpToDate));
+    }
+
+    #[test]
+    fn test_newest_wins_survives_next_sync() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let mut mems: Vec<Member> = dirs
+            .iter()
+            .zip([3, 1])
+            .map(|(dir, priority)| member_in(dir.path(), priority))
+            .collect();
+        for mem in &mut mems {
+            mem.mem_info.mem_conf.dst_option.delete_level = DeleteLevel::Quarantine;
+        }
+        // the main holds an old copy, the lower priority laptop a newer one
+        for (dir, (content, secs)) in dirs.iter().zip([("main", 100), ("edited", 200)]) {
+            let file_abs_path = dir.path().join("a.txt");
+            fs::write(&file_abs_path, content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&file_abs_path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        }
+        let gp = Group {
+            gp_info: GroupInfo {
+                gp_uuid: "gp".to_string(),
+                ..Default::default()
+            },
+            mems,
+        };
+
+        for _ in 0..2 {
+            let vmem =
+                build_virtual_member_from_group(&gp, &opts(ConflictPolicy::NewestWins)).unwrap();
+            gp.sync_from_vmem(&vmem, &SyncOptions::default(), &NoopObserver)
+                .unwrap();
+        }
+        for dir in &dirs {
+            let content = fs::read_to_string(dir.path().join("a.txt")).unwrap();
+            assert_eq!(content, "edited");
+        }
+    }
+
+    #[test]
+    fn test_win_reason() {
+        let a = leaf("/a", 2, 4, 100);
+        let b = leaf("/b", 2, 4, 200);
+        let c = leaf("/c", 1, 4, 300);
+
+        // b beats c by priority, but a came closer
+        for leaves in [[&a, &b, &c], [&c, &b, &a], [&b, &c, &a]] {
+            let leaves: Vec<VirtualLeaf> = leaves.into_iter().cloned().collect();
+            let vmem = vmem_of(&leaves);
+            let (winner, reason) = vmem.resolution(Path::new("d/a.txt")).unwrap();
+            assert_eq!(winner.cab_id, "/b");
+            assert_eq!(reason, WinReason::TieBreak(0, TieBreak::Newest));
+        }
+
+        let vmem = vmem_of(&[b.clone(), c.clone()]);
+        let (_, reason) = vmem.resolution(Path::new("d/a.txt")).unwrap();
+        assert_eq!(reason, WinReason::Priority);
+        let vmem = vmem_of(&[c]);
+        let (_, reason) = vmem.resolution(Path::new("d/a.txt")).unwrap();
+        assert_eq!(reason, WinReason::Only);
+        // same mtime and size, down to the cabinet
+        let vmem = vmem_of(&[leaf("/b", 2, 4, 100), a]);
+        let (winner, reason) = vmem.resolution(Path::new("d/a.txt")).unwrap();
+        assert_eq!(winner.cab_id, "/a");
+        assert_eq!(reason, WinReason::TieBreak(2, TieBreak::Cabinet));
+    }
+
+    #[test]
+    fn test_tie_break_ignores_learn_order() {
+        let a = leaf("/a", 2, 4, 100);
+        let b = leaf("/b", 2, 4, 100);
+        let c = leaf("/c", 2, 4, 200);
+
+        for leaves in [[&a, &b, &c], [&c, &b, &a], [&b, &c, &a]] {
+            let leaves: Vec<VirtualLeaf> = leaves.into_iter().cloned().collect();
+            let vmem = vmem_of(&leaves);
+            // newest first
+            assert_eq!(vmem.virtual_tree[Path::new("d/a.txt")].cab_id, "/c");
+        }
+
+        // equal otherwise, smaller cabinet id
+        assert_eq!(compare_leaves(&a, &b, &[]), Ordering::Greater);
+        assert_eq!(compare_leaves(&b, &a, &default_tie_break()), Ordering::Less);
+    }
+}
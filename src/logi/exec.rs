@@ -1,44 +1,60 @@
+use thiserror::Error;
+use tracing::error;
+
 use crate::logi::{gp::Group, mem::Member};
 
-use std::process::Command;
+use std::{
+    io,
+    process::{Command, ExitStatus},
+};
+
+#[derive(Debug, Error)]
+pub enum ExecError {
+    #[error("Failed to spawn script: {0}")]
+    Spawn(#[from] io::Error),
+
+    #[error("Script failed with {0}")]
+    Status(ExitStatus),
+}
 
 impl Group {
-    pub fn exec(&self, script: &String) {
-        self.mems.iter().for_each(|mem| mem.exec(script));
+    // Runs under every member, the first error is returned once all ran.
+    pub fn exec(&self, script: &str) -> Result<(), ExecError> {
+        let mut result = Ok(());
+        for mem in &self.mems {
+            if let Err(e) = mem.exec(script) {
+                error!("Exec under {:?}: {}", mem.mem_info.cab_info.abs_path, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
     }
 }
 
 impl Member {
-    fn exec(&self, script: &String) {
+    pub fn exec(&self, script: &str) -> Result<(), ExecError> {
         let cd_dir = self.mem_info.cab_info.abs_path.as_path();
 
         // description: cd cd_dir & sh -c <Script>
 
         #[cfg(target_family = "unix")]
-        {
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(script)
-                .current_dir(cd_dir)
-                .status()
-                .expect("failed to execute script");
-
-            if !status.success() {
-                eprintln!("Script failed with exit code {:?}", status.code());
-            }
-        }
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .current_dir(cd_dir)
+            .status()?;
         #[cfg(target_family = "windows")]
-        {
-            let status = Command::new("cmd")
-                .arg("/C")
-                .arg(script)
-                .current_dir(cd_dir)
-                .status()
-                .expect("failed to execute script");
-
-            if !status.success() {
-                eprintln!("Script failed with exit code {:?}", status.code());
-            }
+        let status = Command::new("cmd")
+            .arg("/C")
+            .arg(script)
+            .current_dir(cd_dir)
+            .status()?;
+
+        if !status.success() {
+            return Err(ExecError::Status(status));
         }
+        Ok(())
     }
 }
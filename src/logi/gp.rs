@@ -1,20 +1,52 @@
-use std::collections::BTreeMap;
+use globset::Glob;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, warn};
+
+use std::{cmp::Reverse, collections::BTreeMap, path::PathBuf};
 
 use crate::{
     logi::mem::{Member, MemberInfo},
-    phy::stg::Storage,
+    phy::{
+        cab::{CabinetError, write_cabinet},
+        fs_caps::{FsCapabilities, probe_capabilities},
+        stg::Storage,
+    },
 };
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
+pub enum GroupSelectError {
+    #[error("Unknown group {0}")]
+    Unknown(String),
+
+    #[error("Group {0} is ambiguous, matches {uuids}", uuids = .1.join(", "))]
+    Ambiguous(String, Vec<String>),
+
+    #[error(transparent)]
+    Pattern(#[from] globset::Error),
+}
+
+#[derive(Debug, Serialize)]
 pub struct Group {
     pub gp_info: GroupInfo,
     // relations
     pub mems: Vec<Member>,
 }
 
-#[derive(Debug)]
+// Member cabinets a rename was written to, and those it failed on.
+#[derive(Debug, Default)]
+pub struct RenameResult {
+    pub renamed: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, CabinetError)>,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct GroupInfo {
     pub gp_uuid: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub description: String,
 }
 
 pub fn build_group_map_from_storages(stgs: &Vec<Storage>) -> BTreeMap<String, Group> {
@@ -28,10 +60,10 @@ pub fn build_group_map_from_storages(stgs: &Vec<Storage>) -> BTreeMap<String, Gr
                 let gp = gp_map.entry(gp_uuid.clone()).or_insert_with(|| Group {
                     gp_info: GroupInfo {
                         gp_uuid: gp_uuid.clone(),
+                        ..Default::default()
                     },
                     mems: vec![],
                 });
-
                 gp.mems.push(Member {
                     mem_info: MemberInfo {
                         cab_info: cab.cab_info.clone(),
@@ -40,8 +72,310 @@ pub fn build_group_map_from_storages(stgs: &Vec<Storage>) -> BTreeMap<String, Gr
                     },
                 });
             }
-        } 
-    };
+        }
+    }
 
+    gp_map.values_mut().for_each(Group::learn_name);
     gp_map
 }
+
+impl Group {
+    // Name and description of the top priority member naming the group,
+    // cabinet id breaking ties, so the scan order never decides.
+    pub fn learn_name(&mut self) {
+        let mut mems: Vec<&Member> = self.mems.iter().collect();
+        mems.sort_by_cached_key(|mem| {
+            (
+                Reverse(mem.mem_info.mem_conf.priority),
+                mem.mem_info.cab_id(),
+            )
+        });
+
+        let gp_info = &mut self.gp_info;
+        gp_info.name.clear();
+        gp_info.description.clear();
+        for mem in mems {
+            let mem_conf = &mem.mem_info.mem_conf;
+            if gp_info.name.is_empty() {
+                gp_info.name = mem_conf.group_name.clone();
+            } else if !mem_conf.group_name.is_empty() && mem_conf.group_name != gp_info.name {
+                warn!(
+                    "Group {} is named {:?} in {}, keep {:?} of a higher priority member",
+                    gp_info.gp_uuid,
+                    mem_conf.group_name,
+                    mem.mem_info.cab_info.conf_abs_path.display(),
+                    gp_info.name
+                );
+            }
+            if gp_info.description.is_empty() {
+                gp_info.description = mem_conf.group_description.clone();
+            }
+        }
+    }
+
+    // Write name (and description) into the membership of every member
+    // cabinet. Every cabinet is read and checked first, so none is written
+    // unless all of them can be.
+    pub fn rename(
+        &self,
+        name: &str,
+        description: Option<&str>,
+    ) -> Result<RenameResult, CabinetError> {
+        let mut cabs = vec![];
+        let mut result = Ok(());
+        for mem in &self.mems {
+            match mem.renamed_cabinet(name, description) {
+                Ok(cab) => cabs.push(cab),
+                Err(e) => {
+                    error!(
+                        "Failed to rename group {} in {}: {}",
+                        self.gp_info.gp_uuid,
+                        mem.mem_info.cab_info.abs_path.display(),
+                        e
+                    );
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result?;
+
+        let mut rename_result = RenameResult::default();
+        for cab in cabs {
+            let cab_abs_path = cab.cab_info.abs_path.clone();
+            match write_cabinet(&cab) {
+                Ok(()) => rename_result.renamed.push(cab_abs_path),
+                Err(e) => rename_result.failed.push((cab_abs_path, e)),
+            }
+        }
+        Ok(rename_result)
+    }
+
+    // Probe the filesystems of dst and link members, each once across calls
+    // sharing probed (by fs id). Others keep capabilities assumed from the type.
+    pub fn probe_fs_caps(&mut self, probed: &mut BTreeMap<String, FsCapabilities>) {
+        for mem in &mut self.mems {
+            let mem_conf = &mem.mem_info.mem_conf;
+            if !mem_conf.dst_option.enable && !mem_conf.link_option.enable {
+                continue;
+            }
+            let stg_info = &mut mem.mem_info.stg_info;
+            stg_info.caps = probed
+                .entry(stg_info.fs_id())
+                .or_insert_with(|| probe_capabilities(&stg_info.abs_path, &stg_info.caps.fs_type))
+                .clone();
+        }
+    }
+}
+
+// Groups given by a selector, tried in order: exact uuid, exact name,
+// glob over names and uuids, unique uuid prefix.
+pub fn select_groups<'a>(
+    gp_map: &'a BTreeMap<String, Group>,
+    selector: &str,
+) -> Result<Vec<&'a Group>, GroupSelectError> {
+    if let Some(gp) = gp_map.get(selector) {
+        return Ok(vec![gp]);
+    }
+
+    let named: Vec<&Group> = gp_map
+        .values()
+        .filter(|gp| gp.gp_info.name == selector)
+        .collect();
+    if !named.is_empty() {
+        return unique(selector, named);
+    }
+
+    if selector.contains(['*', '?', '[', '{']) {
+        let matcher = Glob::new(selector)?.compile_matcher();
+        let matched: Vec<&Group> = gp_map
+            .values()
+            .filter(|gp| {
+                (!gp.gp_info.name.is_empty() && matcher.is_match(&gp.gp_info.name))
+                    || matcher.is_match(&gp.gp_info.gp_uuid)
+            })
+            .collect();
+        if matched.is_empty() {
+            return Err(GroupSelectError::Unknown(selector.to_string()));
+        }
+        return Ok(matched);
+    }
+
+    let prefixed: Vec<&Group> = gp_map
+        .values()
+        .filter(|gp| gp.gp_info.gp_uuid.starts_with(selector))
+        .collect();
+    unique(selector, prefixed)
+}
+
+fn unique<'a>(selector: &str, gps: Vec<&'a Group>) -> Result<Vec<&'a Group>, GroupSelectError> {
+    match gps.len() {
+        0 => Err(GroupSelectError::Unknown(selector.to_string())),
+        1 => Ok(gps),
+        _ => Err(GroupSelectError::Ambiguous(
+            selector.to_string(),
+            gps.iter().map(|gp| gp.gp_info.gp_uuid.clone()).collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::{
+        cab::{CabinetInfo, build_cabinet_from_path},
+        cab_conf::{MemberConfig, default_cabinet_config, default_membership},
+        stg::StorageInfo,
+    };
+    use std::path::PathBuf;
+
+    fn gp_map_of(gps: &[(&str, &str)]) -> BTreeMap<String, Group> {
+        gps.iter()
+            .map(|(uuid, name)| {
+                let gp = Group {
+                    gp_info: GroupInfo {
+                        gp_uuid: uuid.to_string(),
+                        name: name.to_string(),
+                        ..Default::default()
+                    },
+                    mems: vec![],
+                };
+                (uuid.to_string(), gp)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_learn_name_by_priority() {
+        let mem = |priority: i32, name: &str| {
+            let mut mem_conf = default_membership();
+            mem_conf.priority = priority;
+            mem_conf.group_name = name.to_string();
+            Member {
+                mem_info: MemberInfo {
+                    cab_info: CabinetInfo {
+                        abs_path: PathBuf::from("/cab"),
+                        conf_abs_path: PathBuf::from("/cab/rsdish.cabinet.toml"),
+                        cab_conf: default_cabinet_config(),
+                    },
+                    mem_conf,
+                    stg_info: StorageInfo {
+                        abs_path: PathBuf::from("/"),
+                        identity: Default::default(),
+                        dev: None,
+                        available_space: None,
+                        caps: Default::default(),
+                    },
+                },
+            }
+        };
+
+        // the main wins wherever it was scanned
+        for mems in [
+            vec![mem(1, "old"), mem(3, "photos"), mem(0, "")],
+            vec![mem(0, ""), mem(3, "photos"), mem(1, "old")],
+        ] {
+            let mut gp = Group {
+                gp_info: GroupInfo::default(),
+                mems,
+            };
+            gp.learn_name();
+            assert_eq!(gp.gp_info.name, "photos");
+        }
+    }
+
+    #[test]
+    fn test_rename_checks_every_member_first() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut gp = Group {
+            gp_info: GroupInfo {
+                gp_uuid: "gp".to_string(),
+                ..Default::default()
+            },
+            mems: vec![],
+        };
+        for name in ["main", "backup"] {
+            let cab_path = tmp_dir.path().join(name);
+            std::fs::create_dir_all(&cab_path).unwrap();
+            let mut cab_conf = default_cabinet_config();
+            cab_conf.memberships.push(MemberConfig {
+                group_uuid: "gp".to_string(),
+                group_name: "old".to_string(),
+                ..default_membership()
+            });
+            let conf_path = cab_path.join(env!("CABINET_CONFIG_NAME"));
+            std::fs::write(&conf_path, toml::to_string(&cab_conf).unwrap()).unwrap();
+            gp.mems.push(Member {
+                mem_info: MemberInfo {
+                    mem_conf: cab_conf.memberships[0].clone(),
+                    cab_info: CabinetInfo {
+                        abs_path: cab_path.clone(),
+                        conf_abs_path: conf_path,
+                        cab_conf,
+                    },
+                    stg_info: StorageInfo {
+                        abs_path: cab_path,
+                        identity: Default::default(),
+                        dev: None,
+                        available_space: None,
+                        caps: Default::default(),
+                    },
+                },
+            });
+        }
+        let group_name = |name: &str| {
+            let cab = build_cabinet_from_path(&tmp_dir.path().join(name)).unwrap();
+            cab.cab_info.cab_conf.memberships[0].group_name.clone()
+        };
+
+        // the backup config is broken, so the main is left as it was
+        let backup_conf = &gp.mems[1].mem_info.cab_info.conf_abs_path;
+        let backup_str = std::fs::read_to_string(backup_conf).unwrap();
+        std::fs::write(backup_conf, "memberships = 1").unwrap();
+        assert!(gp.rename("photos", None).is_err());
+        assert_eq!(group_name("main"), "old");
+
+        std::fs::write(backup_conf, backup_str).unwrap();
+        let renamed = gp.rename("photos", None).unwrap();
+        assert_eq!(renamed.renamed.len(), 2);
+        assert!(renamed.failed.is_empty());
+        assert_eq!(group_name("main"), "photos");
+        assert_eq!(group_name("backup"), "photos");
+    }
+
+    #[test]
+    fn test_select_groups() {
+        let gp_map = gp_map_of(&[
+            ("0199ebad-aaaa", "photos"),
+            ("0199ebad-bbbb", "photos-raw"),
+            ("0199ecd0-cccc", "books"),
+            ("0199ecd0-dddd", "books"),
+        ]);
+        let uuids = |selector: &str| {
+            select_groups(&gp_map, selector).map(|gps| {
+                gps.iter()
+                    .map(|gp| gp.gp_info.gp_uuid.as_str())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(uuids("0199ebad-aaaa").unwrap(), ["0199ebad-aaaa"]);
+        assert_eq!(uuids("photos").unwrap(), ["0199ebad-aaaa"]);
+        assert_eq!(uuids("0199ebad-b").unwrap(), ["0199ebad-bbbb"]);
+        assert_eq!(
+            uuids("photos*").unwrap(),
+            ["0199ebad-aaaa", "0199ebad-bbbb"]
+        );
+        assert!(matches!(
+            uuids("books"),
+            Err(GroupSelectError::Ambiguous(_, c)) if c.len() == 2
+        ));
+        assert!(matches!(
+            uuids("0199"),
+            Err(GroupSelectError::Ambiguous(..))
+        ));
+        assert!(matches!(uuids("music*"), Err(GroupSelectError::Unknown(_))));
+        assert!(matches!(uuids("ffff"), Err(GroupSelectError::Unknown(_))));
+    }
+}
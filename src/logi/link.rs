@@ -1,98 +1,163 @@
-use std::{fs, io, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use tracing::{error, info};
+use thiserror::Error;
+use tracing::{error, info, warn};
 
 use crate::{
     logi::{
         gp::Group,
         mem::Member,
+        plan::SyncReason,
+        report::{Operation, Report, ReportAction, ReportEntry},
         vmem::{VirtualLeaf, VirtualMember},
     },
-    phy::cab_conf::SaveLevel,
+    phy::{
+        cab_conf::{FileFilter, SaveLevel},
+        fs_caps::FsIncompatibility,
+    },
 };
 
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("No parent folder for {0}")]
+    NoParent(PathBuf),
+}
+
 impl Group {
-    pub fn link_from_vmem(&self, vmem: &VirtualMember) {
+    pub fn link_from_vmem(&self, vmem: &VirtualMember) -> Report {
         let link_dsts = self
             .mems
             .iter()
             .filter(|mem| mem.mem_info.mem_conf.link_option.enable);
+
+        let mut report = Report::new(Operation::Link, &self.gp_info.gp_uuid);
         link_dsts.for_each(|link_dst| {
             info!("linking dst {:?}", link_dst.mem_info.cab_info.abs_path);
-            link_dst.link_from_vmem(vmem);
+            link_dst.link_from_vmem(vmem, &mut report);
         });
+        report
     }
 }
 
 impl Member {
-    fn link_from_vmem(&self, vmem: &VirtualMember) {
+    fn link_from_vmem(&self, vmem: &VirtualMember, report: &mut Report) {
         let link_dst_root = self.mem_info.cab_info.abs_path.as_path();
-        let priority = self.mem_info.mem_conf.priority;
+        if !self.mem_info.stg_info.caps.symlinks {
+            warn!(
+                "{} does not support symlinks ({}), nothing is linked",
+                link_dst_root.display(),
+                self.mem_info.stg_info.caps.fs_type
+            );
+        }
+        let incompatible = self.fs_incompatibilities(vmem, true);
+        let filter = self.file_filter();
 
         for leaf in vmem.virtual_tree.values() {
             let target_abs_path = link_dst_root.join(leaf.file_rel_path.as_path());
+            let entry = ReportEntry::new(ReportAction::Link, target_abs_path.clone())
+                .storage(&self.mem_info.stg_info);
 
             match fs::metadata(&target_abs_path) {
-                Ok(_) => (),
-                Err(e) => {
-                    if e.kind() != std::io::ErrorKind::NotFound {
-                        error!("Failed to read meta {}: {}", target_abs_path.display(), e);
-                        continue;
-                    }
-                    match SaveLevel::from(self.mem_info.mem_conf.link_option.save_level) {
-                        SaveLevel::DontSave => self.link_from_leaf(leaf, false),
-                        SaveLevel::SaveHigher => {
-                            self.link_from_leaf(leaf, priority < leaf.priority)
-                        }
-                        SaveLevel::SaveHigherEqual => {
-                            self.link_from_leaf(leaf, priority <= leaf.priority)
-                        }
-                        SaveLevel::SaveAll => self.link_from_leaf(leaf, true),
-                        _ => (),
-                    }
+                Ok(_) => {
+                    report.push(entry.reason("target exists").skipped());
+                    continue;
                 }
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    error!("Failed to read meta {}: {}", target_abs_path.display(), e);
+                    report.push(entry.failed(e));
+                    continue;
+                }
+                Err(_) => (),
             };
+
+            let leaf_priority = vmem.plan_priority(leaf);
+            if let Some(skip_reason) =
+                self.link_skip_reason(leaf, leaf_priority, &incompatible, &filter)
+            {
+                report.push(entry.reason(skip_reason).skipped());
+                continue;
+            }
+
+            match self.link_from_leaf(leaf) {
+                Ok(_) => report.push(entry),
+                Err(e) => {
+                    error!(
+                        "Failed to link from original {} to link {}: {}",
+                        leaf.file_abs_path.display(),
+                        target_abs_path.display(),
+                        e
+                    );
+                    report.push(entry.failed(e));
+                }
+            }
         }
     }
 
-    fn link_from_leaf(&self, leaf: &VirtualLeaf, condition: bool) {
-        if !condition {
-            return;
+    // Why leaf is not linked under this member, none if it should be.
+    fn link_skip_reason(
+        &self,
+        leaf: &VirtualLeaf,
+        leaf_priority: i32,
+        incompatible: &BTreeMap<PathBuf, FsIncompatibility>,
+        filter: &FileFilter,
+    ) -> Option<SyncReason> {
+        if !filter.matches(&leaf.file_rel_path, leaf.size) {
+            return Some(SyncReason::Filtered);
+        }
+        if !self.mem_info.stg_info.caps.symlinks {
+            return Some(SyncReason::Incompatible(FsIncompatibility::NoSymlinks));
+        }
+        if let Some(why) = incompatible.get(&leaf.file_rel_path) {
+            return Some(SyncReason::Incompatible(why.clone()));
         }
+        let priority = self.mem_info.mem_conf.priority;
+        match self.mem_info.mem_conf.link_option.save_level {
+            SaveLevel::DontSave => Some(SyncReason::DontSave),
+            SaveLevel::SaveHigher if priority >= leaf_priority => Some(SyncReason::LowPriority),
+            SaveLevel::SaveHigherEqual if priority > leaf_priority => Some(SyncReason::LowPriority),
+            _ => None,
+        }
+    }
 
+    // Leaves that link would create under this member.
+    pub fn missing_links<'a>(&self, vmem: &'a VirtualMember) -> Vec<&'a VirtualLeaf> {
+        let link_dst_root = self.mem_info.cab_info.abs_path.as_path();
+        let incompatible = self.fs_incompatibilities(vmem, true);
+        let filter = self.file_filter();
+        vmem.virtual_tree
+            .values()
+            .filter(|leaf| {
+                self.link_skip_reason(leaf, vmem.plan_priority(leaf), &incompatible, &filter)
+                    .is_none()
+            })
+            .filter(|leaf| {
+                let target_abs_path = link_dst_root.join(&leaf.file_rel_path);
+                fs::symlink_metadata(target_abs_path)
+                    .is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+            })
+            .collect()
+    }
+
+    fn link_from_leaf(&self, leaf: &VirtualLeaf) -> Result<(), LinkError> {
         let link_dst_root = &self.mem_info.cab_info.abs_path;
         let target_abs_path = link_dst_root.join(&leaf.file_rel_path);
 
-        let target_folder = match target_abs_path.parent() {
-            Some(p) => p,
-            None => {
-                error!("Failed to get parent of {:?}", target_abs_path);
-                return;
-            }
+        let Some(target_folder) = target_abs_path.parent() else {
+            return Err(LinkError::NoParent(target_abs_path));
         };
-        
-        match fs::create_dir_all(target_folder) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to create target folder {:?}: {}", target_folder, e);
-                return;
-            },
-        }
+        fs::create_dir_all(target_folder)?;
 
-        match create_symlink(leaf.file_abs_path.as_path(), target_abs_path.as_path()) {
-            Ok(_) => (),
-            Err(e) => {
-                error!(
-                    "Failed to link from original {} to link {}: {}",
-                    leaf.file_abs_path.display(),
-                    target_abs_path.display(),
-                    e
-                );
-                return;
-            }
-        }
+        create_symlink(leaf.file_abs_path.as_path(), target_abs_path.as_path())?;
+        Ok(())
     }
 }
 
@@ -100,7 +165,7 @@ pub fn create_symlink(original: &Path, link: &Path) -> io::Result<()> {
     // 1. Generate a pseudo-random suffix using timestamp + process ID
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
+        .unwrap_or_default()
         .as_millis();
     let pid = std::process::id();
 
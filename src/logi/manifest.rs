@@ -0,0 +1,319 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, warn};
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::logi::{gp::Group, mem::Member, vmem::VirtualMember};
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+// What a group contained at its last sync, kept in every member cabinet.
+// A path that disappears from every src it was recorded at is a tombstone,
+// until a src holds it again with a newer mtime.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupManifest {
+    pub gp_uuid: String,
+    pub synced_at: SystemTime,
+    // src cabinets that were online at the last sync
+    pub srcs: Vec<ManifestSrc>,
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestSrc {
+    pub cab_abs_path: PathBuf,
+    // absent in manifests written before storages had an identity
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cab_id: String,
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    // srcs holding the path at the last sync, see MemberInfo::cab_id.
+    // Empty in manifests written before, where it is never judged.
+    #[serde(default)]
+    pub cab_ids: Vec<String>,
+    // when the sync that found it deleted ran, none while the path exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<SystemTime>,
+}
+
+pub fn manifest_path(cab_abs_path: &Path, gp_uuid: &str) -> PathBuf {
+    cab_abs_path
+        .join(env!("MANIFEST_DIR_NAME"))
+        .join(format!("{}.json", gp_uuid))
+}
+
+fn holds_manifest(mem: &Member) -> bool {
+    let mem_conf = &mem.mem_info.mem_conf;
+    mem_conf.src_option.enable || mem_conf.dst_option.enable
+}
+
+// Latest manifest among all online members of the group
+pub fn load_group_manifest(gp: &Group) -> Option<GroupManifest> {
+    gp.mems
+        .iter()
+        .filter(|mem| holds_manifest(mem))
+        .filter_map(|mem| {
+            let mft_path = manifest_path(&mem.mem_info.cab_info.abs_path, &gp.gp_info.gp_uuid);
+            let mft_str = match fs::read_to_string(&mft_path) {
+                Ok(t) => t,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        error!("Failed to read manifest {}: {}", mft_path.display(), e);
+                    }
+                    return None;
+                }
+            };
+            serde_json::from_str::<GroupManifest>(&mft_str)
+                .inspect_err(|e| warn!("Discard corrupted manifest {}: {}", mft_path.display(), e))
+                .ok()
+        })
+        .max_by_key(|mft| mft.synced_at)
+}
+
+pub fn write_group_manifest(gp: &Group, mft: &GroupManifest) {
+    let mft_json = match serde_json::to_vec(mft) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to serialize manifest of {}: {}", gp.gp_info.gp_uuid, e);
+            return;
+        }
+    };
+
+    gp.mems.iter().filter(|mem| holds_manifest(mem)).for_each(|mem| {
+        let mft_path = manifest_path(&mem.mem_info.cab_info.abs_path, &gp.gp_info.gp_uuid);
+        if let Err(e) = write_manifest_file(&mft_path, &mft_json) {
+            error!("Failed to write manifest {}: {}", mft_path.display(), e);
+        }
+    });
+}
+
+fn write_manifest_file(mft_path: &Path, mft_json: &[u8]) -> Result<(), ManifestError> {
+    if let Some(parent) = mft_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = mft_path.with_extension(format!("json{}", env!("SYNC_TMP_SUFFIX")));
+    fs::write(&tmp_path, mft_json)?;
+    fs::rename(&tmp_path, mft_path)?;
+    Ok(())
+}
+
+impl ManifestSrc {
+    // same cabinet, even when its drive is mounted elsewhere now
+    fn is_same(&self, other: &ManifestSrc) -> bool {
+        (!self.cab_id.is_empty() && self.cab_id == other.cab_id)
+            || self.cab_abs_path == other.cab_abs_path
+    }
+}
+
+fn online_srcs(gp: &Group) -> Vec<ManifestSrc> {
+    gp.mems
+        .iter()
+        .filter(|mem| mem.mem_info.mem_conf.src_option.enable)
+        .map(|mem| ManifestSrc {
+            cab_abs_path: mem.mem_info.cab_info.abs_path.clone(),
+            cab_id: mem.mem_info.cab_id(),
+            priority: mem.mem_info.mem_conf.priority,
+        })
+        .collect()
+}
+
+impl VirtualMember {
+    // Move paths deleted at every src that held them into tombstones.
+    // A path is only judged when those srcs are all online and none of
+    // them holds it anymore, otherwise it is carried over untouched.
+    pub fn apply_manifest(&mut self, gp: &Group, mft: &GroupManifest) {
+        let online_ids: Vec<String> = online_srcs(gp).into_iter().map(|src| src.cab_id).collect();
+        let now = SystemTime::now();
+
+        for (path, entry) in &mft.entries {
+            // left out by a src filter, still there
+            if self.filtered.contains(path) {
+                continue;
+            }
+
+            match entry.deleted_at {
+                // judged deleted at an earlier sync, unless made again since
+                Some(deleted_at) => {
+                    if self
+                        .virtual_tree
+                        .get(path)
+                        .is_some_and(|leaf| leaf.mtime > deleted_at)
+                    {
+                        continue;
+                    }
+                    self.virtual_tree.remove(path);
+                    self.tombstones.insert(path.clone(), entry.clone());
+                }
+                // some online src still holds it
+                None if self.virtual_tree.contains_key(path) => {}
+                // unknown holders, or one of them may hold it offline
+                None if entry.cab_ids.is_empty()
+                    || !entry.cab_ids.iter().all(|id| online_ids.contains(id)) =>
+                {
+                    self.pending.insert(path.clone(), entry.clone());
+                }
+                None => {
+                    let tombstone = ManifestEntry {
+                        cab_ids: entry.cab_ids.clone(),
+                        deleted_at: Some(now),
+                    };
+                    self.tombstones.insert(path.clone(), tombstone);
+                }
+            }
+        }
+    }
+
+    pub fn to_manifest(&self, gp: &Group) -> GroupManifest {
+        let mut srcs = online_srcs(gp);
+        // keep offline srcs, so their files are not taken as deleted
+        if let Some(prev) = &self.manifest {
+            prev.srcs.iter().for_each(|src| {
+                if !srcs.iter().any(|s| s.is_same(src)) {
+                    srcs.push(src.clone());
+                }
+            });
+        }
+
+        let mut entries: BTreeMap<PathBuf, ManifestEntry> = self
+            .virtual_tree
+            .keys()
+            .map(|path| {
+                let entry = ManifestEntry {
+                    cab_ids: self.holders.get(path).cloned().unwrap_or_default(),
+                    deleted_at: None,
+                };
+                (path.clone(), entry)
+            })
+            .collect();
+        entries.extend(self.pending.clone());
+        entries.extend(self.tombstones.clone());
+
+        GroupManifest {
+            gp_uuid: gp.gp_info.gp_uuid.clone(),
+            synced_at: SystemTime::now(),
+            srcs,
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        logi::{gp::GroupInfo, mem::MemberInfo, vmem::VirtualLeaf},
+        phy::{
+            cab::CabinetInfo,
+            cab_conf::{default_cabinet_config, default_membership},
+            stg::StorageInfo,
+        },
+    };
+
+    #[test]
+    fn test_apply_manifest_tombstones() {
+        let deleted_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let leaf = |name: &str, secs: u64| VirtualLeaf {
+            file_rel_path: PathBuf::from(name),
+            file_abs_path: PathBuf::from("/mirror").join(name),
+            priority: 1,
+            size: 1,
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            hash: None,
+            cab_id: "mirror".to_string(),
+        };
+        let mut vmem = VirtualMember::default();
+        for (name, secs) in [("stale.txt", 500), ("remade.txt", 2000), ("kept.txt", 500)] {
+            vmem.virtual_tree
+                .insert(PathBuf::from(name), leaf(name, secs));
+        }
+
+        let held_by = |ids: &[&str]| ManifestEntry {
+            cab_ids: ids.iter().map(|id| id.to_string()).collect(),
+            deleted_at: None,
+        };
+        let tombstone = ManifestEntry {
+            deleted_at: Some(deleted_at),
+            ..held_by(&["main"])
+        };
+        let mft = GroupManifest {
+            gp_uuid: "gp".to_string(),
+            synced_at: deleted_at,
+            srcs: vec![],
+            entries: BTreeMap::from([
+                (PathBuf::from("stale.txt"), tombstone.clone()),
+                (PathBuf::from("remade.txt"), tombstone.clone()),
+                // recorded at the main, still held by the mirror
+                (PathBuf::from("kept.txt"), held_by(&["main", "mirror"])),
+                (PathBuf::from("gone.txt"), held_by(&["main", "mirror"])),
+                (PathBuf::from("offline.txt"), held_by(&["main", "laptop"])),
+                // written before holders were recorded
+                (PathBuf::from("unknown.txt"), held_by(&[])),
+            ]),
+        };
+        let gp = Group {
+            gp_info: GroupInfo::default(),
+            mems: ["main", "mirror"]
+                .iter()
+                .map(|name| {
+                    let mut mem_conf = default_membership();
+                    mem_conf.src_option.enable = true;
+                    let mut cab_conf = default_cabinet_config();
+                    cab_conf.cabinet_uuid = name.to_string();
+                    Member {
+                        mem_info: MemberInfo {
+                            mem_conf,
+                            cab_info: CabinetInfo {
+                                abs_path: PathBuf::from("/").join(name),
+                                conf_abs_path: PathBuf::from("/").join(name).join("conf"),
+                                cab_conf,
+                            },
+                            stg_info: StorageInfo {
+                                abs_path: PathBuf::from("/"),
+                                identity: Default::default(),
+                                dev: None,
+                                available_space: None,
+                                caps: Default::default(),
+                            },
+                        },
+                    }
+                })
+                .collect(),
+        };
+        vmem.apply_manifest(&gp, &mft);
+
+        // old copy on the mirror stays deleted, with the time it was first judged
+        assert_eq!(
+            vmem.tombstones.get(Path::new("stale.txt")),
+            Some(&tombstone)
+        );
+        assert!(!vmem.virtual_tree.contains_key(Path::new("stale.txt")));
+        // written after the deletion, so it is a new file
+        assert!(!vmem.tombstones.contains_key(Path::new("remade.txt")));
+        assert!(vmem.virtual_tree.contains_key(Path::new("remade.txt")));
+        assert!(!vmem.tombstones.contains_key(Path::new("kept.txt")));
+        // judged now
+        assert!(vmem.tombstones[Path::new("gone.txt")].deleted_at.unwrap() > deleted_at);
+        // the laptop may still hold it, and nothing is known of the last one
+        assert!(vmem.pending.contains_key(Path::new("offline.txt")));
+        assert!(vmem.pending.contains_key(Path::new("unknown.txt")));
+        assert_eq!(vmem.tombstones.len(), 2);
+    }
+}
@@ -1,14 +1,76 @@
-use crate::phy::{cab::CabinetInfo, cab_conf::MemberConfig, stg::StorageInfo};
+use serde::Serialize;
+use tracing::error;
 
-#[derive(Debug)]
+use crate::phy::{
+  cab::{Cabinet, CabinetError, CabinetInfo, build_cabinet_from_path},
+  cab_conf::{FileFilter, MemberConfig},
+  stg::StorageInfo,
+};
+
+#[derive(Debug, Serialize)]
 pub struct Member {
   pub mem_info: MemberInfo,
   // relations
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MemberInfo {
   pub mem_conf: MemberConfig,
   pub cab_info: CabinetInfo,
   pub stg_info: StorageInfo,
+}
+
+impl Member {
+  // Files this membership holds, every file if its filter is invalid
+  pub fn file_filter(&self) -> FileFilter {
+    self.mem_info.mem_conf.filter_option.build().unwrap_or_else(|e| {
+      error!(
+        "Invalid filter_option of {}: {}. Using none.",
+        self.mem_info.cab_info.abs_path.display(),
+        e
+      );
+      FileFilter::default()
+    })
+  }
+
+  // The cabinet of this membership with the group renamed, checked but
+  // not written yet.
+  pub fn renamed_cabinet(
+    &self,
+    name: &str,
+    description: Option<&str>,
+  ) -> Result<Cabinet, CabinetError> {
+    let gp_uuid = &self.mem_info.mem_conf.group_uuid;
+    let mut cab = build_cabinet_from_path(&self.mem_info.cab_info.abs_path)?;
+    cab
+      .cab_info
+      .cab_conf
+      .memberships
+      .iter_mut()
+      .filter(|m| m.group_uuid == *gp_uuid)
+      .for_each(|m| {
+        m.group_name = name.to_string();
+        if let Some(description) = description {
+          m.group_description = description.to_string();
+        }
+      });
+    cab.cab_info.cab_conf.verify()?;
+    Ok(cab)
+  }
+}
+
+impl MemberInfo {
+  // cabinet uuid, or the storage id and the cabinet path within it for
+  // configs not given one yet, so a remounted drive keeps its ids
+  pub fn cab_id(&self) -> String {
+    if !self.cab_info.cab_conf.cabinet_uuid.is_empty() {
+      return self.cab_info.cab_conf.cabinet_uuid.clone();
+    }
+    let rel_path = self
+      .cab_info
+      .abs_path
+      .strip_prefix(&self.stg_info.abs_path)
+      .unwrap_or(&self.cab_info.abs_path);
+    format!("{}:{}", self.stg_info.stg_id(), rel_path.display())
+  }
 }
\ No newline at end of file
@@ -0,0 +1,448 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    logi::{
+        gp::Group,
+        mem::Member,
+        sync::{SyncOptions, need_sync},
+        vmem::{VirtualLeaf, VirtualMember},
+    },
+    phy::{
+        cab_conf::{CoverLevel, DeleteLevel, FileFilter, SaveLevel},
+        fs_caps::FsIncompatibility,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    Copy,
+    Overwrite,
+    Quarantine,
+    Delete,
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncReason {
+    // target is missing and save level allows saving it
+    Missing,
+    // target exists, leaf has higher priority and differs
+    Outdated,
+    // target exists and strict mode re-copies it
+    Strict,
+    // target exists and matches the leaf
+    UpToDate,
+    DontSave,
+    DontCover,
+    // leaf priority is not high enough for the save/cover level
+    LowPriority,
+    // src was deleted since the last sync
+    Deleted,
+    DontDelete,
+    // the dst is a src of the group itself, its copy is never deleted
+    HeldBySrc,
+    UnreadableTarget(String),
+    // comparing by hash failed, the file is left as is
+    Unhashable(String),
+    // left out of a partial sync, the dst storage is full
+    NoSpace,
+    // the dst filesystem can't hold the file
+    Incompatible(FsIncompatibility),
+    // left out by the filter_option of the membership
+    Filtered,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncStep {
+    pub file_rel_path: PathBuf,
+    // none for tombstones
+    pub leaf: Option<VirtualLeaf>,
+    pub target_abs_path: PathBuf,
+    pub action: SyncAction,
+    pub reason: SyncReason,
+}
+
+#[derive(Debug)]
+pub struct MemberSyncPlan<'a> {
+    pub dst: &'a Member,
+    pub steps: Vec<SyncStep>,
+}
+
+#[derive(Debug)]
+pub struct GroupSyncPlan<'a> {
+    pub gp: &'a Group,
+    pub mem_plans: Vec<MemberSyncPlan<'a>>,
+}
+
+impl fmt::Display for SyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            SyncAction::Copy => "copy",
+            SyncAction::Overwrite => "overwrite",
+            SyncAction::Quarantine => "quarantine",
+            SyncAction::Delete => "delete",
+            SyncAction::Skip => "skip",
+        };
+        f.pad(s)
+    }
+}
+
+impl fmt::Display for SyncReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncReason::Missing => write!(f, "missing on dst"),
+            SyncReason::Outdated => write!(f, "dst is outdated"),
+            SyncReason::Strict => write!(f, "strict mode"),
+            SyncReason::UpToDate => write!(f, "dst is up to date"),
+            SyncReason::DontSave => write!(f, "save level is DontSave"),
+            SyncReason::DontCover => write!(f, "cover level is DontCover"),
+            SyncReason::LowPriority => write!(f, "src priority is not high enough"),
+            SyncReason::Deleted => write!(f, "deleted at src"),
+            SyncReason::DontDelete => write!(f, "delete level is DontDelete"),
+            SyncReason::HeldBySrc => write!(f, "dst is a src holding it"),
+            SyncReason::UnreadableTarget(e) => write!(f, "failed to read dst meta: {}", e),
+            SyncReason::Unhashable(e) => write!(f, "failed to hash {}", e),
+            SyncReason::NoSpace => write!(f, "not enough free space on dst"),
+            SyncReason::Incompatible(e) => write!(f, "dst filesystem: {}", e),
+            SyncReason::Filtered => write!(f, "excluded by membership filter"),
+        }
+    }
+}
+
+impl Group {
+    pub fn plan_sync_from_vmem(
+        &self,
+        vmem: &VirtualMember,
+        sync_opts: &SyncOptions,
+    ) -> GroupSyncPlan<'_> {
+        let mem_plans = self
+            .mems
+            .iter()
+            .filter(|mem| mem.mem_info.mem_conf.dst_option.enable)
+            .map(|dst| dst.plan_sync_from_vmem(vmem, sync_opts))
+            .collect();
+
+        GroupSyncPlan {
+            gp: self,
+            mem_plans,
+        }
+    }
+}
+
+impl Member {
+    pub fn plan_sync_from_vmem(
+        &self,
+        vmem: &VirtualMember,
+        sync_opts: &SyncOptions,
+    ) -> MemberSyncPlan<'_> {
+        let filter = self.file_filter();
+        let mut steps: Vec<SyncStep> = vmem
+            .virtual_tree
+            .values()
+            .map(|leaf| {
+                self.plan_sync_from_leaf(leaf, vmem.plan_priority(leaf), &filter, sync_opts)
+            })
+            .collect();
+        steps.extend(
+            vmem.tombstones
+                .keys()
+                .filter_map(|path| self.plan_sync_from_tombstone(path, &filter)),
+        );
+
+        // skipped here rather than failing deep in the copy
+        let incompatible = self.fs_incompatibilities(vmem, false);
+        steps
+            .iter_mut()
+            .filter(|step| matches!(step.action, SyncAction::Copy | SyncAction::Overwrite))
+            .for_each(|step| {
+                if let Some(why) = incompatible.get(&step.file_rel_path) {
+                    step.action = SyncAction::Skip;
+                    step.reason = SyncReason::Incompatible(why.clone());
+                }
+            });
+
+        MemberSyncPlan { dst: self, steps }
+    }
+
+    // Leaves the filesystem of this member can't hold, by relative path.
+    // Links take no space, so only their names are checked.
+    pub(crate) fn fs_incompatibilities(
+        &self,
+        vmem: &VirtualMember,
+        links: bool,
+    ) -> BTreeMap<PathBuf, FsIncompatibility> {
+        let caps = &self.mem_info.stg_info.caps;
+        let filter = self.file_filter();
+        let leaves: Vec<&VirtualLeaf> = vmem
+            .virtual_tree
+            .values()
+            .filter(|leaf| filter.matches(&leaf.file_rel_path, leaf.size))
+            .collect();
+        let mut found: BTreeMap<PathBuf, FsIncompatibility> = caps
+            .case_collisions(leaves.iter().map(|leaf| leaf.file_rel_path.as_path()))
+            .into_iter()
+            .map(|(path, first)| (path, FsIncompatibility::CaseCollision(first)))
+            .collect();
+        let mut listed = BTreeMap::new();
+        for leaf in leaves {
+            let size = if links { 0 } else { leaf.size };
+            if let Some(why) = caps.check(&leaf.file_rel_path, size) {
+                found.insert(leaf.file_rel_path.clone(), why);
+            } else if let Some(existing) = caps.existing_case_collision(
+                &self.mem_info.cab_info.abs_path,
+                &leaf.file_rel_path,
+                &mut listed,
+            ) {
+                found.insert(
+                    leaf.file_rel_path.clone(),
+                    FsIncompatibility::CaseCollision(existing),
+                );
+            }
+        }
+        found
+    }
+
+    fn plan_sync_from_tombstone(
+        &self,
+        file_rel_path: &Path,
+        filter: &FileFilter,
+    ) -> Option<SyncStep> {
+        let target_abs_path = self.mem_info.cab_info.abs_path.join(file_rel_path);
+
+        // nothing to delete
+        let target_meta = fs::symlink_metadata(&target_abs_path).ok()?;
+        if !target_meta.is_file() {
+            return None;
+        }
+
+        let (action, reason) = match self.mem_info.mem_conf.dst_option.delete_level {
+            _ if !filter.matches(file_rel_path, target_meta.len()) => {
+                (SyncAction::Skip, SyncReason::Filtered)
+            }
+            _ if self.mem_info.mem_conf.src_option.enable => {
+                (SyncAction::Skip, SyncReason::HeldBySrc)
+            }
+            DeleteLevel::DontDelete => (SyncAction::Skip, SyncReason::DontDelete),
+            DeleteLevel::Quarantine => (SyncAction::Quarantine, SyncReason::Deleted),
+            DeleteLevel::Delete => (SyncAction::Delete, SyncReason::Deleted),
+        };
+
+        Some(SyncStep {
+            file_rel_path: file_rel_path.to_path_buf(),
+            leaf: None,
+            target_abs_path,
+            action,
+            reason,
+        })
+    }
+
+    fn plan_sync_from_leaf(
+        &self,
+        leaf: &VirtualLeaf,
+        leaf_priority: i32,
+        filter: &FileFilter,
+        sync_opts: &SyncOptions,
+    ) -> SyncStep {
+        let dst_option = &self.mem_info.mem_conf.dst_option;
+        let priority = self.mem_info.mem_conf.priority;
+        let target_abs_path = self
+            .mem_info
+            .cab_info
+            .abs_path
+            .join(leaf.file_rel_path.as_path());
+        let compare_mode = sync_opts.compare_mode.unwrap_or(dst_option.compare_mode);
+
+        let (action, reason) = match fs::metadata(&target_abs_path) {
+            _ if !filter.matches(&leaf.file_rel_path, leaf.size) => {
+                (SyncAction::Skip, SyncReason::Filtered)
+            }
+            Ok(t) => match dst_option.cover_level {
+                CoverLevel::DontCover => (SyncAction::Skip, SyncReason::DontCover),
+                CoverLevel::HigherCover if priority >= leaf_priority => {
+                    (SyncAction::Skip, SyncReason::LowPriority)
+                }
+                CoverLevel::HigherCover if sync_opts.strict => {
+                    (SyncAction::Overwrite, SyncReason::Strict)
+                }
+                CoverLevel::HigherCover => match need_sync(
+                    leaf,
+                    &target_abs_path,
+                    &t,
+                    compare_mode,
+                    self.mem_info.stg_info.caps.mtime_granularity,
+                ) {
+                    Ok(true) => (SyncAction::Overwrite, SyncReason::Outdated),
+                    Ok(false) => (SyncAction::Skip, SyncReason::UpToDate),
+                    Err(e) => (SyncAction::Skip, SyncReason::Unhashable(e.to_string())),
+                },
+            },
+            Err(e) if e.kind() != io::ErrorKind::NotFound => (
+                SyncAction::Skip,
+                SyncReason::UnreadableTarget(e.to_string()),
+            ),
+            Err(_) => {
+                let allowed = match dst_option.save_level {
+                    SaveLevel::DontSave => Err(SyncReason::DontSave),
+                    SaveLevel::SaveHigher => Ok(priority < leaf_priority),
+                    SaveLevel::SaveHigherEqual => Ok(priority <= leaf_priority),
+                    SaveLevel::SaveAll => Ok(true),
+                };
+                match allowed {
+                    Ok(true) => (SyncAction::Copy, SyncReason::Missing),
+                    Ok(false) => (SyncAction::Skip, SyncReason::LowPriority),
+                    Err(reason) => (SyncAction::Skip, reason),
+                }
+            }
+        };
+
+        SyncStep {
+            file_rel_path: leaf.file_rel_path.clone(),
+            leaf: Some(leaf.clone()),
+            target_abs_path,
+            action,
+            reason,
+        }
+    }
+}
+
+impl MemberSyncPlan<'_> {
+    pub fn count(&self, action: SyncAction) -> usize {
+        self.steps.iter().filter(|s| s.action == action).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logi::{gp::GroupInfo, mem::MemberInfo},
+        phy::{
+            cab::CabinetInfo,
+            cab_conf::{MemberConfig, default_membership},
+            stg::StorageInfo,
+        },
+    };
+    use tempfile::tempdir;
+
+    fn member(cab_abs_path: &Path, mem_conf: MemberConfig) -> Member {
+        Member {
+            mem_info: MemberInfo {
+                mem_conf,
+                cab_info: CabinetInfo {
+                    abs_path: cab_abs_path.to_path_buf(),
+                    conf_abs_path: cab_abs_path.join(env!("CABINET_CONFIG_NAME")),
+                    cab_conf: crate::phy::cab_conf::default_cabinet_config(),
+                },
+                stg_info: StorageInfo {
+                    abs_path: cab_abs_path.to_path_buf(),
+                    identity: Default::default(),
+                    dev: None,
+                    available_space: None,
+                    caps: Default::default(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_plan_sync_from_vmem() {
+        let src_dir = tempdir().unwrap();
+        let dst_dir = tempdir().unwrap();
+
+        for name in ["new.txt", "same.txt", "changed.txt"] {
+            fs::write(src_dir.path().join(name), name).unwrap();
+        }
+        fs::write(dst_dir.path().join("same.txt"), "same.txt").unwrap();
+        fs::write(dst_dir.path().join("changed.txt"), "old").unwrap();
+
+        let mut vmem = VirtualMember {
+            highest_priority: 3,
+            ..Default::default()
+        };
+        for name in ["new.txt", "same.txt", "changed.txt"] {
+            vmem.virtual_tree.insert(
+                PathBuf::from(name),
+                VirtualLeaf {
+                    file_rel_path: PathBuf::from(name),
+                    file_abs_path: src_dir.path().join(name),
+                    priority: 3,
+                    size: name.len() as u64,
+                    mtime: fs::metadata(src_dir.path().join(name))
+                        .unwrap()
+                        .modified()
+                        .unwrap(),
+                    hash: None,
+                    cab_id: "src".to_string(),
+                },
+            );
+        }
+
+        let mut mem_conf = default_membership();
+        mem_conf.priority = 1;
+        mem_conf.dst_option.enable = true;
+        mem_conf.dst_option.cover_level = CoverLevel::HigherCover;
+        mem_conf.dst_option.save_level = SaveLevel::SaveHigherEqual;
+
+        let gp = Group {
+            gp_info: GroupInfo {
+                gp_uuid: mem_conf.group_uuid.clone(),
+                ..Default::default()
+            },
+            mems: vec![member(dst_dir.path(), mem_conf)],
+        };
+
+        let gp_plan = gp.plan_sync_from_vmem(&vmem, &SyncOptions::default());
+        let steps = &gp_plan.mem_plans[0].steps;
+        let action_of = |name: &str| {
+            steps
+                .iter()
+                .find(|s| s.file_rel_path == Path::new(name))
+                .map(|s| s.action)
+                .unwrap()
+        };
+
+        assert_eq!(action_of("new.txt"), SyncAction::Copy);
+        assert_eq!(action_of("changed.txt"), SyncAction::Overwrite);
+        // dst copy was written after src, same size
+        assert_eq!(action_of("same.txt"), SyncAction::Skip);
+
+        // nothing touched on disk
+        assert!(!dst_dir.path().join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_tombstone_spares_srcs() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let mems = dirs
+            .iter()
+            .zip([true, false])
+            .map(|(dir, src)| {
+                fs::write(dir.path().join("gone.txt"), "gone").unwrap();
+                let mut mem_conf = default_membership();
+                mem_conf.src_option.enable = src;
+                mem_conf.dst_option.enable = true;
+                mem_conf.dst_option.delete_level = DeleteLevel::Delete;
+                member(dir.path(), mem_conf)
+            })
+            .collect();
+        let mut vmem = VirtualMember::default();
+        vmem.tombstones
+            .insert(PathBuf::from("gone.txt"), Default::default());
+        let gp = Group {
+            gp_info: GroupInfo::default(),
+            mems,
+        };
+
+        let gp_plan = gp.plan_sync_from_vmem(&vmem, &SyncOptions::default());
+        let step_of = |dst: usize| {
+            let step = &gp_plan.mem_plans[dst].steps[0];
+            (step.action, step.reason.clone())
+        };
+        assert_eq!(step_of(0), (SyncAction::Skip, SyncReason::HeldBySrc));
+        assert_eq!(step_of(1), (SyncAction::Delete, SyncReason::Deleted));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::logi::plan::{GroupSyncPlan, SyncAction};
+
+// Work of a sync run, known before anything is copied.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncTotals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncProgress {
+    pub files_done: u64,
+    pub files_total: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // dst path being written
+    pub current_file: PathBuf,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncSummary {
+    // copied or overwritten
+    pub copied: u64,
+    // quarantined or deleted
+    pub deleted: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+// Receives progress of a sync run. Called from every copy worker.
+pub trait SyncObserver: Sync {
+    fn on_start(&self, _totals: &SyncTotals) {}
+    fn on_progress(&self, _progress: &SyncProgress) {}
+    fn on_finish(&self, _summary: &SyncSummary) {}
+}
+
+pub struct NoopObserver;
+
+impl SyncObserver for NoopObserver {}
+
+impl SyncProgress {
+    // bytes per second
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes_done as f64 / secs
+    }
+}
+
+impl GroupSyncPlan<'_> {
+    pub fn totals(&self) -> SyncTotals {
+        let mut totals = SyncTotals::default();
+        self.mem_plans
+            .iter()
+            .flat_map(|mem_plan| &mem_plan.steps)
+            .filter(|step| matches!(step.action, SyncAction::Copy | SyncAction::Overwrite))
+            .for_each(|step| {
+                totals.files += 1;
+                totals.bytes += step.leaf.as_ref().map_or(0, |leaf| leaf.size);
+            });
+        totals
+    }
+}
+
+// Shared counters of a running sync, feeding the observer.
+pub(crate) struct ProgressTracker<'a> {
+    observer: &'a dyn SyncObserver,
+    totals: SyncTotals,
+    started: Instant,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    copied: AtomicU64,
+    deleted: AtomicU64,
+    skipped: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub fn start(observer: &'a dyn SyncObserver, totals: SyncTotals) -> Self {
+        observer.on_start(&totals);
+        ProgressTracker {
+            observer,
+            totals,
+            started: Instant::now(),
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            copied: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_bytes(&self, n: u64, current_file: &Path) {
+        let bytes_done = self.bytes_done.fetch_add(n, Ordering::Relaxed) + n;
+        self.notify(bytes_done, current_file);
+    }
+
+    pub fn step_done(&self, action: SyncAction, ok: bool, current_file: &Path) {
+        let counter = match (action, ok) {
+            (_, false) => &self.failed,
+            (SyncAction::Copy | SyncAction::Overwrite, true) => {
+                self.files_done.fetch_add(1, Ordering::Relaxed);
+                &self.copied
+            }
+            (SyncAction::Quarantine | SyncAction::Delete, true) => &self.deleted,
+            (SyncAction::Skip, true) => &self.skipped,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if matches!(action, SyncAction::Copy | SyncAction::Overwrite) {
+            self.notify(self.bytes_done.load(Ordering::Relaxed), current_file);
+        }
+    }
+
+    fn notify(&self, bytes_done: u64, current_file: &Path) {
+        self.observer.on_progress(&SyncProgress {
+            files_done: self.files_done.load(Ordering::Relaxed),
+            files_total: self.totals.files,
+            bytes_done,
+            bytes_total: self.totals.bytes,
+            current_file: current_file.to_path_buf(),
+            elapsed: self.started.elapsed(),
+        });
+    }
+
+    pub fn finish(self) -> SyncSummary {
+        let summary = SyncSummary {
+            copied: self.copied.into_inner(),
+            deleted: self.deleted.into_inner(),
+            skipped: self.skipped.into_inner(),
+            failed: self.failed.into_inner(),
+            bytes: self.bytes_done.into_inner(),
+            elapsed: self.started.elapsed(),
+        };
+        self.observer.on_finish(&summary);
+        summary
+    }
+}
@@ -0,0 +1,174 @@
+use serde::Serialize;
+
+use std::{fmt, path::PathBuf};
+
+use crate::{logi::plan::SyncAction, phy::stg::StorageInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Sync,
+    Link,
+    Clean,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReportAction {
+    Copy,
+    Overwrite,
+    Quarantine,
+    Delete,
+    Skip,
+    Link,
+    Clean,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Done,
+    Skipped,
+    Failed,
+}
+
+// What happened to one target file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    pub action: ReportAction,
+    pub target_abs_path: PathBuf,
+    // storage holding the target, which outlives its mount path
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub stg_id: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Result of one operation over a group.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub operation: Operation,
+    pub gp_uuid: String,
+    pub done: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub entries: Vec<ReportEntry>,
+}
+
+impl From<SyncAction> for ReportAction {
+    fn from(value: SyncAction) -> Self {
+        match value {
+            SyncAction::Copy => ReportAction::Copy,
+            SyncAction::Overwrite => ReportAction::Overwrite,
+            SyncAction::Quarantine => ReportAction::Quarantine,
+            SyncAction::Delete => ReportAction::Delete,
+            SyncAction::Skip => ReportAction::Skip,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operation::Sync => "sync",
+            Operation::Link => "link",
+            Operation::Clean => "clean",
+        };
+        f.pad(s)
+    }
+}
+
+impl ReportEntry {
+    pub fn new(action: impl Into<ReportAction>, target_abs_path: PathBuf) -> Self {
+        ReportEntry {
+            action: action.into(),
+            target_abs_path,
+            stg_id: String::new(),
+            outcome: Outcome::Done,
+            reason: None,
+            error: None,
+        }
+    }
+
+    pub fn storage(mut self, stg_info: &StorageInfo) -> Self {
+        self.stg_id = stg_info.stg_id();
+        self
+    }
+
+    pub fn reason(mut self, reason: impl fmt::Display) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub fn skipped(mut self) -> Self {
+        self.outcome = Outcome::Skipped;
+        self
+    }
+
+    pub fn failed(mut self, error: impl fmt::Display) -> Self {
+        self.outcome = Outcome::Failed;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+impl Report {
+    pub fn new(operation: Operation, gp_uuid: &str) -> Self {
+        Report {
+            operation,
+            gp_uuid: gp_uuid.to_string(),
+            done: 0,
+            skipped: 0,
+            failed: 0,
+            entries: vec![],
+        }
+    }
+
+    pub fn push(&mut self, entry: ReportEntry) {
+        match entry.outcome {
+            Outcome::Done => self.done += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.failed > 0
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &ReportEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.outcome == Outcome::Failed)
+    }
+}
+
+impl Extend<ReportEntry> for Report {
+    fn extend<T: IntoIterator<Item = ReportEntry>>(&mut self, iter: T) {
+        iter.into_iter().for_each(|entry| self.push(entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_counts() {
+        let mut report = Report::new(Operation::Sync, "gp");
+        report.extend([
+            ReportEntry::new(SyncAction::Copy, PathBuf::from("/dst/a")).reason("missing on dst"),
+            ReportEntry::new(SyncAction::Skip, PathBuf::from("/dst/b")).skipped(),
+            ReportEntry::new(SyncAction::Overwrite, PathBuf::from("/dst/c")).failed("denied"),
+        ]);
+
+        assert_eq!((report.done, report.skipped, report.failed), (1, 1, 1));
+        assert!(report.has_failures());
+        let failed: Vec<_> = report.failures().collect();
+        assert_eq!(failed[0].error.as_deref(), Some("denied"));
+    }
+}
@@ -0,0 +1,260 @@
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tracing::warn;
+
+use std::{cmp::Reverse, collections::BTreeMap, fmt, path::PathBuf};
+
+use crate::{
+    logi::{
+        mem::Member,
+        plan::{GroupSyncPlan, SyncAction, SyncReason, SyncStep},
+    },
+    phy::trash::expired_trash,
+};
+
+// Free space of one filesystem against what a sync would write to it.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpaceCheck {
+    pub stg_abs_path: PathBuf,
+    pub fs_id: String,
+    // bytes copied or overwritten on the filesystem, by every dst on it
+    pub needed: u64,
+    pub available: u64,
+    // largest min_free of the dst cabinets on the filesystem
+    pub reserve: u64,
+    // trash of the dst cabinets past its retention, pruned before writing
+    pub reclaimable: u64,
+}
+
+impl SpaceCheck {
+    pub fn usable(&self) -> u64 {
+        self.available
+            .saturating_add(self.reclaimable)
+            .saturating_sub(self.reserve)
+    }
+
+    pub fn fits(&self) -> bool {
+        self.needed <= self.usable()
+    }
+}
+
+impl fmt::Display for SpaceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} needs {} but has {} free ({} reserved",
+            self.stg_abs_path.display(),
+            HumanBytes(self.needed),
+            HumanBytes(self.available),
+            HumanBytes(self.reserve)
+        )?;
+        if self.reclaimable > 0 {
+            write!(f, ", {} in expired trash", HumanBytes(self.reclaimable))?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl SyncStep {
+    // bytes this step writes to its dst. A version replaced by an overwrite
+    // is only freed after the copy, or kept in trash, so it is not deducted.
+    pub fn bytes_written(&self) -> u64 {
+        match self.action {
+            SyncAction::Copy | SyncAction::Overwrite => {
+                self.leaf.as_ref().map_or(0, |leaf| leaf.size)
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl GroupSyncPlan<'_> {
+    // One check per filesystem holding a dst, so storage paths on one disk
+    // share its free space. Filesystems whose free space is unknown are left out.
+    pub fn space_checks(&self) -> Vec<SpaceCheck> {
+        let mut checks: BTreeMap<String, SpaceCheck> = BTreeMap::new();
+        for mem_plan in &self.mem_plans {
+            let mem_info = &mem_plan.dst.mem_info;
+            let fs_id = mem_info.stg_info.fs_id();
+            if !checks.contains_key(&fs_id) {
+                let Some(available) = mem_info.stg_info.available_space else {
+                    warn!(
+                        "Unknown free space of {}, skip space check",
+                        mem_info.stg_info.abs_path.display()
+                    );
+                    continue;
+                };
+                let check = SpaceCheck {
+                    stg_abs_path: mem_info.stg_info.abs_path.clone(),
+                    fs_id: fs_id.clone(),
+                    needed: 0,
+                    available,
+                    reserve: 0,
+                    reclaimable: 0,
+                };
+                checks.insert(fs_id.clone(), check);
+            }
+
+            if let Some(check) = checks.get_mut(&fs_id) {
+                check.needed += mem_plan
+                    .steps
+                    .iter()
+                    .map(|s| s.bytes_written())
+                    .sum::<u64>();
+                let min_free = mem_info.cab_info.cab_conf.min_free.unwrap_or(0);
+                check.reserve = check.reserve.max(min_free);
+                check.reclaimable += mem_plan.dst.expired_trash_size();
+            }
+        }
+        checks.into_values().collect()
+    }
+
+    // Skip writes to storages short of space until the rest fits, keeping
+    // files of higher src priority first. Returns the number of skipped steps.
+    pub fn fit_to_space(&mut self, checks: &[SpaceCheck]) -> usize {
+        let mut skipped = 0;
+        for check in checks.iter().filter(|check| !check.fits()) {
+            let mut writes: Vec<&mut SyncStep> = self
+                .mem_plans
+                .iter_mut()
+                .filter(|mem_plan| mem_plan.dst.mem_info.stg_info.fs_id() == check.fs_id)
+                .flat_map(|mem_plan| mem_plan.steps.iter_mut())
+                .filter(|step| step.bytes_written() > 0)
+                .collect();
+            writes.sort_by_key(|step| {
+                let priority = step.leaf.as_ref().map_or(i32::MIN, |leaf| leaf.priority);
+                (Reverse(priority), step.target_abs_path.clone())
+            });
+
+            let mut budget = check.usable();
+            let mut left_out = 0;
+            for step in writes {
+                let size = step.bytes_written();
+                if size <= budget {
+                    budget -= size;
+                    continue;
+                }
+                step.action = SyncAction::Skip;
+                step.reason = SyncReason::NoSpace;
+                left_out += 1;
+            }
+            warn!(
+                "Not enough space on {}, {} file(s) are left out",
+                check.stg_abs_path.display(),
+                left_out
+            );
+            skipped += left_out;
+        }
+        skipped
+    }
+}
+
+impl Member {
+    // bytes the trash retention policy frees before the next writes
+    fn expired_trash_size(&self) -> u64 {
+        let cab_info = &self.mem_info.cab_info;
+        match expired_trash(&cab_info.abs_path, &cab_info.cab_conf.trash) {
+            Ok(expired) => expired.iter().map(|entry| entry.meta.size).sum(),
+            Err(e) => {
+                warn!(
+                    "Failed to read trash of {}: {}",
+                    cab_info.abs_path.display(),
+                    e
+                );
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logi::{
+            gp::{Group, GroupInfo},
+            mem::{Member, MemberInfo},
+            plan::MemberSyncPlan,
+            vmem::VirtualLeaf,
+        },
+        phy::{
+            cab::CabinetInfo,
+            cab_conf::{default_cabinet_config, default_membership},
+            stg::StorageInfo,
+        },
+    };
+    use std::{path::Path, time::SystemTime};
+
+    fn copy_step(name: &str, priority: i32, size: u64) -> SyncStep {
+        SyncStep {
+            file_rel_path: PathBuf::from(name),
+            leaf: Some(VirtualLeaf {
+                file_rel_path: PathBuf::from(name),
+                file_abs_path: Path::new("/src").join(name),
+                priority,
+                size,
+                mtime: SystemTime::UNIX_EPOCH,
+                hash: None,
+                cab_id: "src".to_string(),
+            }),
+            target_abs_path: Path::new("/dst").join(name),
+            action: SyncAction::Copy,
+            reason: SyncReason::Missing,
+        }
+    }
+
+    #[test]
+    fn test_fit_to_space() {
+        let dst = Member {
+            mem_info: MemberInfo {
+                mem_conf: default_membership(),
+                cab_info: CabinetInfo {
+                    abs_path: PathBuf::from("/dst"),
+                    conf_abs_path: Path::new("/dst").join(env!("CABINET_CONFIG_NAME")),
+                    cab_conf: default_cabinet_config(),
+                },
+                stg_info: StorageInfo {
+                    abs_path: PathBuf::from("/dst"),
+                    identity: Default::default(),
+                    dev: None,
+                    available_space: None,
+                    caps: Default::default(),
+                },
+            },
+        };
+        let gp = Group {
+            gp_info: GroupInfo::default(),
+            mems: vec![],
+        };
+        let mut gp_plan = GroupSyncPlan {
+            gp: &gp,
+            mem_plans: vec![MemberSyncPlan {
+                dst: &dst,
+                steps: vec![
+                    copy_step("low.bin", 1, 50),
+                    copy_step("high.bin", 3, 60),
+                    copy_step("mid.bin", 2, 30),
+                ],
+            }],
+        };
+
+        let check = SpaceCheck {
+            stg_abs_path: PathBuf::from("/dst"),
+            fs_id: dst.mem_info.stg_info.fs_id(),
+            needed: 140,
+            available: 100,
+            reserve: 10,
+            reclaimable: 10,
+        };
+        assert!(!check.fits());
+        assert_eq!(gp_plan.fit_to_space(&[check]), 1);
+
+        let skipped: Vec<&Path> = gp_plan.mem_plans[0]
+            .steps
+            .iter()
+            .filter(|step| step.reason == SyncReason::NoSpace)
+            .map(|step| step.file_rel_path.as_path())
+            .collect();
+        assert_eq!(skipped, [Path::new("low.bin")]);
+    }
+}
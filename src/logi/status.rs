@@ -0,0 +1,125 @@
+use serde::Serialize;
+
+use std::{collections::BTreeSet, path::PathBuf, time::SystemTime};
+
+use crate::{
+    logi::{gp::Group, plan::SyncAction, sync::SyncOptions, vmem::VirtualMember},
+    phy::cab_conf::{CompareMode, MemberConfig},
+    user::registry::{Registry, RegistryMember},
+};
+
+// Health of a group as seen from the online members.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupStatus {
+    pub gp_uuid: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    // online members by role, one member may count for several
+    pub srcs: usize,
+    pub dsts: usize,
+    pub links: usize,
+    // distinct online filesystems holding the files as src or dst
+    pub copies: usize,
+    pub files: usize,
+    pub conflicts: usize,
+    pub dst_status: Vec<DstStatus>,
+    pub missing_links: usize,
+    pub pending_cleans: usize,
+    pub last_synced: Option<SystemTime>,
+    // known members whose drive is not plugged in
+    pub offline: Vec<RegistryMember>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DstStatus {
+    pub cab_abs_path: PathBuf,
+    // files sync would copy, overwrite or delete
+    pub outdated: usize,
+}
+
+impl GroupStatus {
+    // a single online copy is lost with its storage
+    pub fn at_risk(&self) -> bool {
+        self.copies <= 1
+    }
+
+    pub fn outdated(&self) -> usize {
+        self.dst_status.iter().map(|dst| dst.outdated).sum()
+    }
+}
+
+impl Group {
+    // Dsts are compared by size and mtime only, so status never reads file contents.
+    pub fn status(&self, vmem: &VirtualMember, reg: &Registry) -> GroupStatus {
+        let role_count = |f: fn(&MemberConfig) -> bool| {
+            self.mems
+                .iter()
+                .filter(|mem| f(&mem.mem_info.mem_conf))
+                .count()
+        };
+        let copies: BTreeSet<String> = self
+            .mems
+            .iter()
+            .filter(|mem| {
+                let mem_conf = &mem.mem_info.mem_conf;
+                mem_conf.src_option.enable || mem_conf.dst_option.enable
+            })
+            .map(|mem| mem.mem_info.stg_info.fs_id())
+            .collect();
+
+        let sync_opts = SyncOptions {
+            compare_mode: Some(CompareMode::Meta),
+            ..Default::default()
+        };
+        let dst_status = self
+            .plan_sync_from_vmem(vmem, &sync_opts)
+            .mem_plans
+            .iter()
+            .map(|mem_plan| DstStatus {
+                cab_abs_path: mem_plan.dst.mem_info.cab_info.abs_path.clone(),
+                outdated: mem_plan
+                    .steps
+                    .iter()
+                    .filter(|step| step.action != SyncAction::Skip)
+                    .count(),
+            })
+            .collect();
+
+        let missing_links = self
+            .mems
+            .iter()
+            .filter(|mem| mem.mem_info.mem_conf.link_option.enable)
+            .map(|mem| mem.missing_links(vmem).len())
+            .sum();
+        let pending_cleans = self
+            .mems
+            .iter()
+            .filter(|mem| mem.mem_info.mem_conf.dst_option.enable)
+            .map(|mem| mem.clean_candidates(vmem).len())
+            .sum();
+
+        let offline: Vec<RegistryMember> = reg.offline_members(self).into_iter().cloned().collect();
+        // a group with every member offline has no manifest at hand
+        let last_synced = vmem
+            .manifest
+            .as_ref()
+            .map(|mft| mft.synced_at)
+            .or_else(|| offline.iter().filter_map(|m| m.last_synced).max());
+
+        GroupStatus {
+            gp_uuid: self.gp_info.gp_uuid.clone(),
+            name: self.gp_info.name.clone(),
+            srcs: role_count(|c| c.src_option.enable),
+            dsts: role_count(|c| c.dst_option.enable),
+            links: role_count(|c| c.link_option.enable),
+            copies: copies.len(),
+            files: vmem.virtual_tree.len(),
+            conflicts: vmem.conflicts.len(),
+            dst_status,
+            missing_links,
+            pending_cleans,
+            last_synced,
+            offline,
+        }
+    }
+}
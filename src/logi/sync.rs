@@ -1,130 +1,676 @@
-use tracing::{error, info};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
 
 use std::{
-    fs::{self, Metadata},
-    time::SystemTime,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, FileTimes, Metadata, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     logi::{
         gp::Group,
+        manifest::write_group_manifest,
         mem::Member,
+        plan::{GroupSyncPlan, MemberSyncPlan, SyncAction, SyncReason, SyncStep},
+        progress::{ProgressTracker, SyncObserver},
+        report::{Operation, Report, ReportEntry},
+        space::SpaceCheck,
         vmem::{VirtualLeaf, VirtualMember},
     },
-    phy::cab_conf::{CoverLevel, SaveLevel},
+    phy::{
+        cab_conf::{CompareMode, DstOption},
+        trash::{TrashEntry, TrashError, TrashReason, prune_trash, trash_file},
+    },
 };
 
-pub fn need_sync(src: &VirtualLeaf, dst_meta: &Metadata, strict: bool) -> bool {
-    if strict {
-        return true;
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Trash(#[from] TrashError),
+
+    #[error("No parent folder for {0}")]
+    NoParent(PathBuf),
+
+    #[error("Not enough free space: {}, pass --partial to copy what fits", checks_list(.0))]
+    NoSpace(Vec<SpaceCheck>),
+}
+
+fn checks_list(checks: &[SpaceCheck]) -> String {
+    checks
+        .iter()
+        .map(|check| check.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CopyOptions {
+    pub keep_mtime: bool,
+    pub keep_permissions: bool,
+    pub keep_owner: bool,
+}
+
+impl From<&DstOption> for CopyOptions {
+    fn from(dst_option: &DstOption) -> Self {
+        CopyOptions {
+            keep_mtime: dst_option.keep_mtime,
+            keep_permissions: dst_option.keep_permissions,
+            keep_owner: dst_option.keep_owner,
+        }
     }
+}
 
-    let src_meta = match fs::metadata(&src.file_abs_path) {
-        Ok(m) => m,
-        Err(_) => return false,
-    };
+#[derive(Debug, Clone, Copy)]
+pub struct SyncOptions {
+    // re-copy everything regardless of dst state
+    pub strict: bool,
+    // overrides dst_option.compare_mode of every dst
+    pub compare_mode: Option<CompareMode>,
+    // filesystems written at the same time
+    pub jobs: usize,
+    // files written at the same time on one filesystem
+    pub writers_per_storage: usize,
+    // copy what fits on a full dst storage instead of refusing the run
+    pub partial: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            strict: false,
+            compare_mode: None,
+            jobs: 1,
+            writers_per_storage: 1,
+            partial: false,
+        }
+    }
+}
 
-    let src_size = src_meta.len();
-    let dst_size = dst_meta.len();
+impl SyncOptions {
+    // whether any dst of gp compares by content hash
+    pub fn needs_hash(&self, gp: &Group) -> bool {
+        gp.mems
+            .iter()
+            .filter(|mem| mem.mem_info.mem_conf.dst_option.enable)
+            .any(|dst| {
+                self.compare_mode
+                    .unwrap_or(dst.mem_info.mem_conf.dst_option.compare_mode)
+                    == CompareMode::Hash
+            })
+    }
+}
 
-    let src_mtime = src_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
-    let dst_mtime = dst_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+// Whether the dst copy differs from src. With meta, a dst mtime behind src
+// by no more than the granularity of the dst filesystem is only truncated.
+// Fails when hashing either side fails.
+pub fn need_sync(
+    src: &VirtualLeaf,
+    dst_abs_path: &Path,
+    dst_meta: &Metadata,
+    compare_mode: CompareMode,
+    mtime_granularity: Duration,
+) -> io::Result<bool> {
+    if src.size != dst_meta.len() {
+        return Ok(true);
+    }
 
-    if src_size != dst_size || src_mtime > dst_mtime {
-        true
-    } else {
-        false
+    match compare_mode {
+        CompareMode::Meta => {
+            let dst_mtime = dst_meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            Ok(src
+                .mtime
+                .duration_since(dst_mtime)
+                .is_ok_and(|behind| behind > mtime_granularity))
+        }
+        CompareMode::Hash => {
+            let hash_of = |path: &Path| {
+                hash_file(path)
+                    .map(|h| h.to_hex().to_string())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+            };
+            let src_hash = match &src.hash {
+                Some(t) => t.clone(),
+                None => hash_of(&src.file_abs_path)?,
+            };
+            Ok(src_hash != hash_of(dst_abs_path)?)
+        }
     }
 }
 
+pub fn hash_file(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize())
+}
+
 impl Group {
-    pub fn sync_from_vmem(&self, vmem: &VirtualMember, strict: bool) {
-        let dsts = self
-            .mems
-            .iter()
-            .filter(|mem| mem.mem_info.mem_conf.dst_option.enable);
-        dsts.for_each(|dst| {
-            info!("run sync for dst {:?}", dst.mem_info.cab_info.abs_path);
-            dst.sync_from_vmem(vmem, strict);
+    pub fn sync_from_vmem(
+        &self,
+        vmem: &VirtualMember,
+        sync_opts: &SyncOptions,
+        observer: &dyn SyncObserver,
+    ) -> Result<Report, SyncError> {
+        let mut gp_plan = self.plan_sync_from_vmem(vmem, sync_opts);
+        // nothing is written when a dst storage can't hold its share
+        let short: Vec<SpaceCheck> = gp_plan
+            .space_checks()
+            .into_iter()
+            .filter(|check| !check.fits())
+            .collect();
+        if !short.is_empty() {
+            if !sync_opts.partial {
+                return Err(SyncError::NoSpace(short));
+            }
+            gp_plan.fit_to_space(&short);
+        }
+
+        let report = gp_plan.execute(sync_opts, observer);
+        write_group_manifest(self, &vmem.to_manifest(self));
+        Ok(report)
+    }
+}
+
+// Run f on every item with up to `workers` threads.
+fn run_pool<T: Sync>(items: &[T], workers: usize, f: impl Fn(&T) + Sync) {
+    let workers = workers.clamp(1, items.len().max(1));
+    if workers == 1 {
+        items.iter().for_each(f);
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    f(item);
+                }
+            });
+        }
+    });
+}
+
+impl GroupSyncPlan<'_> {
+    // Dsts on the same filesystem share one lane and are synced one after
+    // another, even from distinct storage paths. Lanes of distinct
+    // filesystems run in parallel up to jobs.
+    pub fn execute(&self, sync_opts: &SyncOptions, observer: &dyn SyncObserver) -> Report {
+        let tracker = ProgressTracker::start(observer, self.totals());
+        let entries = Mutex::new(Vec::new());
+
+        let mut lanes: BTreeMap<String, Vec<&MemberSyncPlan>> = BTreeMap::new();
+        self.mem_plans.iter().for_each(|mem_plan| {
+            lanes
+                .entry(mem_plan.dst.mem_info.stg_info.fs_id())
+                .or_default()
+                .push(mem_plan);
+        });
+        let lanes: Vec<Vec<&MemberSyncPlan>> = lanes.into_values().collect();
+
+        run_pool(&lanes, sync_opts.jobs, |lane| {
+            // expired trash is counted as free by the space check
+            lane.iter().for_each(|mem_plan| mem_plan.dst.prune_trash());
+            lane.iter().for_each(|mem_plan| {
+                info!(
+                    "run sync for dst {:?}",
+                    mem_plan.dst.mem_info.cab_info.abs_path
+                );
+                let mem_entries = mem_plan.execute(sync_opts.writers_per_storage, &tracker);
+                if let Ok(mut entries) = entries.lock() {
+                    entries.extend(mem_entries);
+                }
+                mem_plan.dst.prune_trash();
+            });
         });
+        tracker.finish();
+
+        // lanes and writers finish in any order
+        let mut entries = entries.into_inner().unwrap_or_default();
+        entries.sort_by(|a, b| a.target_abs_path.cmp(&b.target_abs_path));
+        let mut report = Report::new(Operation::Sync, &self.gp.gp_info.gp_uuid);
+        report.extend(entries);
+        report
     }
 }
 
-impl Member {
-    fn sync_from_vmem(&self, vmem: &VirtualMember, strict: bool) {
-        let dst_root = self.mem_info.cab_info.abs_path.as_path();
-        let priority = self.mem_info.mem_conf.priority;
-
-        for leaf in vmem.virtual_tree.values() {
-            let target_abs_path = dst_root.join(leaf.file_rel_path.as_path());
-
-            match fs::metadata(&target_abs_path) {
-                Ok(t) => match CoverLevel::from(self.mem_info.mem_conf.dst_option.cover_level) {
-                    CoverLevel::DontCover => self.sync_from_leaf(leaf, false),
-                    CoverLevel::HigherCover => self.sync_from_leaf(
-                        leaf,
-                        priority < leaf.priority && need_sync(leaf, &t, strict),
-                    ),
-                    _ => (),
-                },
+impl MemberSyncPlan<'_> {
+    pub(crate) fn execute(&self, writers: usize, tracker: &ProgressTracker) -> Vec<ReportEntry> {
+        self.clean_tmp_files();
+        let incompatible = self
+            .steps
+            .iter()
+            .filter(|step| matches!(step.reason, SyncReason::Incompatible(_)))
+            .count();
+        if incompatible > 0 {
+            warn!(
+                "{} file(s) can't be written to {} ({}), skipped",
+                incompatible,
+                self.dst.mem_info.cab_info.abs_path.display(),
+                self.dst.mem_info.stg_info.caps.fs_type
+            );
+        }
+        let entries = Mutex::new(Vec::with_capacity(self.steps.len()));
+
+        run_pool(&self.steps, writers, |step| {
+            let result = self.execute_step(step, tracker);
+            tracker.step_done(step.action, result.is_ok(), &step.target_abs_path);
+
+            let entry = ReportEntry::new(step.action, step.target_abs_path.clone())
+                .storage(&self.dst.mem_info.stg_info)
+                .reason(&step.reason);
+            let entry = match result {
+                Ok(_) if step.action == SyncAction::Skip => entry.skipped(),
+                Ok(_) => entry,
                 Err(e) => {
-                    if e.kind() != std::io::ErrorKind::NotFound {
-                        error!("Failed to read meta {}: {}", target_abs_path.display(), e);
-                        continue;
-                    }
-                    match SaveLevel::from(self.mem_info.mem_conf.dst_option.save_level) {
-                        SaveLevel::DontSave => self.sync_from_leaf(leaf, false),
-                        SaveLevel::SaveHigher => {
-                            self.sync_from_leaf(leaf, priority < leaf.priority)
-                        }
-                        SaveLevel::SaveHigherEqual => {
-                            self.sync_from_leaf(leaf, priority <= leaf.priority)
-                        }
-                        SaveLevel::SaveAll => self.sync_from_leaf(leaf, true),
-                        _ => (),
-                    }
+                    error!(
+                        "Failed to sync {} ({}): {}",
+                        step.target_abs_path.display(),
+                        step.action,
+                        e
+                    );
+                    entry.failed(e)
                 }
             };
+            if let Ok(mut entries) = entries.lock() {
+                entries.push(entry);
+            }
+        });
+
+        entries.into_inner().unwrap_or_default()
+    }
+
+    fn execute_step(&self, step: &SyncStep, tracker: &ProgressTracker) -> Result<(), SyncError> {
+        match step.action {
+            SyncAction::Copy | SyncAction::Overwrite => {
+                if let Some(leaf) = &step.leaf {
+                    self.dst
+                        .sync_from_leaf(leaf, &|n| tracker.add_bytes(n, &step.target_abs_path))?;
+                    debug!(
+                        "Synced file {:?} to {:?}",
+                        leaf.file_abs_path, step.target_abs_path
+                    );
+                }
+            }
+            SyncAction::Quarantine => {
+                let entry = self.dst.quarantine_from_step(step)?;
+                info!(
+                    "Quarantined deleted file {} to {}",
+                    step.target_abs_path.display(),
+                    entry.entry_abs_path.display()
+                );
+            }
+            SyncAction::Delete => {
+                fs::remove_file(&step.target_abs_path)?;
+                info!("Removed deleted file {}", step.target_abs_path.display());
+            }
+            SyncAction::Skip => match &step.reason {
+                SyncReason::UnreadableTarget(e) => {
+                    return Err(io::Error::other(format!("failed to read meta: {}", e)).into());
+                }
+                SyncReason::Unhashable(e) => {
+                    return Err(io::Error::other(format!("failed to hash: {}", e)).into());
+                }
+                _ => {}
+            },
         }
+        Ok(())
     }
 
-    fn sync_from_leaf(&self, leaf: &VirtualLeaf, condition: bool) {
-        if !condition {
-            return;
+    // Remove temp files an interrupted copy left next to the files this plan
+    // writes. Only their folders are listed, not the whole cabinet.
+    fn clean_tmp_files(&self) {
+        let mut written: BTreeMap<&Path, BTreeSet<Cow<'_, str>>> = BTreeMap::new();
+        self.steps
+            .iter()
+            .filter(|step| matches!(step.action, SyncAction::Copy | SyncAction::Overwrite))
+            .for_each(|step| {
+                let target = &step.target_abs_path;
+                if let (Some(dir), Some(name)) = (target.parent(), target.file_name()) {
+                    written
+                        .entry(dir)
+                        .or_default()
+                        .insert(name.to_string_lossy());
+                }
+            });
+
+        for (dir, names) in written {
+            // a folder not made yet holds nothing
+            let Ok(ents) = fs::read_dir(dir) else {
+                continue;
+            };
+            for ent in ents.flatten() {
+                let ent_name = ent.file_name();
+                let is_tmp = tmp_target_name(&ent_name.to_string_lossy())
+                    .is_some_and(|name| names.contains(name));
+                if !is_tmp || !ent.file_type().is_ok_and(|t| t.is_file()) {
+                    continue;
+                }
+
+                match fs::remove_file(ent.path()) {
+                    Ok(_) => info!("Removed leftover temp file {}", ent.path().display()),
+                    Err(e) => error!("Failed to remove {}: {}", ent.path().display(), e),
+                }
+            }
         }
+    }
+}
 
+impl Member {
+    // on_bytes is fed the size of every chunk written
+    fn sync_from_leaf(&self, leaf: &VirtualLeaf, on_bytes: &dyn Fn(u64)) -> Result<u64, SyncError> {
         let dst_root = &self.mem_info.cab_info.abs_path;
         let target_abs_path = dst_root.join(&leaf.file_rel_path);
 
-        let target_folder = match target_abs_path.parent() {
-            Some(p) => p,
-            None => {
-                error!("Failed to get parent of {:?}", target_abs_path);
-                return;
+        let Some(target_folder) = target_abs_path.parent() else {
+            return Err(SyncError::NoParent(target_abs_path));
+        };
+        fs::create_dir_all(target_folder)?;
+
+        let copy_opts = CopyOptions::from(&self.mem_info.mem_conf.dst_option);
+        // keep the replaced version in trash
+        let replaces = self.mem_info.cab_info.cab_conf.trash.enable
+            && fs::symlink_metadata(&target_abs_path).is_ok();
+        let before_replace = || {
+            if !replaces {
+                return Ok(());
             }
+            trash_file(
+                dst_root,
+                &self.mem_info.mem_conf.group_uuid,
+                &leaf.file_rel_path,
+                TrashReason::Overwrite,
+            )
+            .map(|_| ())
+            .map_err(io::Error::other)
         };
 
-        match fs::create_dir_all(target_folder) {
+        let n = copy_atomic_with(
+            &leaf.file_abs_path,
+            &target_abs_path,
+            &copy_opts,
+            before_replace,
+            on_bytes,
+        )?;
+        Ok(n)
+    }
+
+    fn quarantine_from_step(&self, step: &SyncStep) -> Result<TrashEntry, SyncError> {
+        let entry = trash_file(
+            &self.mem_info.cab_info.abs_path,
+            &self.mem_info.mem_conf.group_uuid,
+            &step.file_rel_path,
+            TrashReason::Delete,
+        )?;
+        Ok(entry)
+    }
+
+    fn prune_trash(&self) {
+        let trash_conf = &self.mem_info.cab_info.cab_conf.trash;
+        match prune_trash(&self.mem_info.cab_info.abs_path, trash_conf) {
+            Ok(pruned) if !pruned.is_empty() => info!(
+                "Pruned {} trash entries of {}",
+                pruned.len(),
+                self.mem_info.cab_info.abs_path.display()
+            ),
             Ok(_) => (),
-            Err(e) => {
-                error!("Failed to create target folder {:?}: {}", target_folder, e);
-                return;
-            }
+            Err(e) => error!(
+                "Failed to prune trash of {}: {}",
+                self.mem_info.cab_info.abs_path.display(),
+                e
+            ),
         }
+    }
+}
 
-        match fs::copy(&leaf.file_abs_path, &target_abs_path) {
-            Ok(_) => {
-                info!(
-                    "Synced file {:?} to {:?}",
-                    leaf.file_abs_path, target_abs_path
-                );
-            }
-            Err(e) => {
-                error!(
-                    "Failed to copy file {:?} to {:?}: {}",
-                    leaf.file_abs_path, target_abs_path, e
-                );
+fn tmp_path_for(target: &Path) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let pid = std::process::id();
+
+    // ex: .book.pdf.1234_1698324000000.rsdish-tmp
+    target.with_file_name(format!(
+        ".{}.{}_{}{}",
+        target
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default(),
+        pid,
+        timestamp,
+        env!("SYNC_TMP_SUFFIX")
+    ))
+}
+
+// Name of the file a temp file of tmp_path_for was made for.
+fn tmp_target_name(tmp_name: &str) -> Option<&str> {
+    let stem = tmp_name
+        .strip_prefix('.')?
+        .strip_suffix(env!("SYNC_TMP_SUFFIX"))?;
+    Some(stem.rsplit_once('.')?.0)
+}
+
+pub fn copy_atomic(src: &Path, dst: &Path, opts: &CopyOptions) -> io::Result<u64> {
+    copy_atomic_with(src, dst, opts, || Ok(()), &|_| ())
+}
+
+// before_replace runs once the copy is complete, right before dst is replaced.
+// on_bytes is fed the size of every chunk written.
+pub fn copy_atomic_with(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    before_replace: impl FnOnce() -> io::Result<()>,
+    on_bytes: &dyn Fn(u64),
+) -> io::Result<u64> {
+    // 1. Copy into a temporary sibling, so dst is never seen half-written
+    let tmp = tmp_path_for(dst);
+
+    let result = copy_content(src, &tmp, on_bytes).and_then(|n| {
+        // 2. Carry src meta, then flush to disk before it becomes visible
+        let tmp_file = OpenOptions::new().write(true).open(&tmp)?;
+        copy_meta(src, &tmp, &tmp_file, opts)?;
+        tmp_file.sync_all()?;
+        // 3. Atomically replace dst
+        before_replace()?;
+        fs::rename(&tmp, dst)?;
+        Ok(n)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+
+    // 4. Persist the rename itself
+    #[cfg(unix)]
+    if let Some(parent) = dst.parent() {
+        let _ = fs::File::open(parent).and_then(|d| d.sync_all());
+    }
+
+    result
+}
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+fn copy_content(src: &Path, tmp: &Path, on_bytes: &dyn Fn(u64)) -> io::Result<u64> {
+    let mut src_file = File::open(src)?;
+    let mut tmp_file = File::create(tmp)?;
+
+    let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+    let mut total: u64 = 0;
+    loop {
+        let n = match src_file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        tmp_file.write_all(&buf[..n])?;
+        total += n as u64;
+        on_bytes(n as u64);
+    }
+    Ok(total)
+}
+
+fn copy_meta(src: &Path, tmp: &Path, tmp_file: &File, opts: &CopyOptions) -> io::Result<()> {
+    let src_meta = fs::metadata(src)?;
+
+    if opts.keep_mtime {
+        let mut times = FileTimes::new().set_modified(src_meta.modified()?);
+        if let Ok(atime) = src_meta.accessed() {
+            times = times.set_accessed(atime);
+        }
+        tmp_file.set_times(times)?;
+    }
+
+    // filesystems like FAT refuse modes, the content is copied anyway
+    if opts.keep_permissions
+        && let Err(e) = tmp_file.set_permissions(src_meta.permissions())
+    {
+        warn!("Failed to keep permissions of {}: {}", src.display(), e);
+    }
+
+    if opts.keep_owner {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if let Err(e) =
+                std::os::unix::fs::chown(tmp, Some(src_meta.uid()), Some(src_meta.gid()))
+            {
+                warn!("Failed to keep owner of {}: {}", src.display(), e);
             }
         }
+        #[cfg(not(unix))]
+        warn!(
+            "Keeping owner is not supported on this platform: {}",
+            tmp.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn opts() -> CopyOptions {
+        CopyOptions {
+            keep_mtime: true,
+            keep_permissions: true,
+            keep_owner: false,
+        }
+    }
+
+    #[test]
+    fn test_copy_atomic() {
+        let tmp_dir = tempdir().unwrap();
+        let src = tmp_dir.path().join("src.txt");
+        let dst = tmp_dir.path().join("dst.txt");
+
+        fs::write(&src, "new content").unwrap();
+        fs::write(&dst, "old").unwrap();
+        let src_mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(src_mtime)
+            .unwrap();
+
+        let opts = opts();
+        let n = copy_atomic(&src, &dst, &opts).unwrap();
+
+        assert_eq!(n, "new content".len() as u64);
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "new content");
+        assert_eq!(fs::metadata(&dst).unwrap().modified().unwrap(), src_mtime);
+        let leftovers = fs::read_dir(tmp_dir.path())
+            .unwrap()
+            .flatten()
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .ends_with(env!("SYNC_TMP_SUFFIX"))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn test_tmp_target_name() {
+        let tmp = tmp_path_for(Path::new("/dst/book.v2.pdf"));
+        let tmp_name = tmp.file_name().unwrap().to_string_lossy();
+        assert_eq!(tmp_target_name(&tmp_name), Some("book.v2.pdf"));
+        assert_eq!(tmp_target_name("book.v2.pdf"), None);
+    }
+
+    #[test]
+    fn test_copy_atomic_missing_src() {
+        let tmp_dir = tempdir().unwrap();
+        let dst = tmp_dir.path().join("dst.txt");
+        fs::write(&dst, "old").unwrap();
+
+        let opts = opts();
+        assert!(copy_atomic(&tmp_dir.path().join("nope"), &dst, &opts).is_err());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "old");
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_need_sync_truncated_mtime() {
+        let tmp_dir = tempdir().unwrap();
+        let dst = tmp_dir.path().join("dst.txt");
+        fs::write(&dst, "same").unwrap();
+        // FAT keeps even seconds only
+        let dst_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(&dst)
+            .unwrap()
+            .set_modified(dst_mtime)
+            .unwrap();
+        let src = VirtualLeaf {
+            file_rel_path: PathBuf::from("dst.txt"),
+            file_abs_path: PathBuf::from("/src/dst.txt"),
+            priority: 1,
+            size: 4,
+            mtime: dst_mtime + Duration::from_millis(1500),
+            hash: None,
+            cab_id: "src".to_string(),
+        };
+
+        let dst_meta = fs::metadata(&dst).unwrap();
+        let fat = Duration::from_secs(2);
+        assert!(!need_sync(&src, &dst, &dst_meta, CompareMode::Meta, fat).unwrap());
+        let exact = Duration::ZERO;
+        assert!(need_sync(&src, &dst, &dst_meta, CompareMode::Meta, exact).unwrap());
+        // the src file is gone
+        assert!(need_sync(&src, &dst, &dst_meta, CompareMode::Hash, exact).is_err());
+    }
+
+    #[test]
+    fn test_run_pool_visits_each_item_once() {
+        let items: Vec<usize> = (0..100).collect();
+        let sum = AtomicUsize::new(0);
+
+        run_pool(&items, 4, |i| {
+            sum.fetch_add(*i, Ordering::Relaxed);
+        });
+
+        assert_eq!(sum.into_inner(), 4950);
     }
 }
@@ -1,13 +1,48 @@
-use ignore::{DirEntry, WalkBuilder};
-use tracing::{error, info};
-
-use std::{collections::BTreeMap, env, fs, path::PathBuf};
-
-use crate::logi::{gp::Group, mem::Member};
-
+use tracing::{error, info, warn};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::{
+    logi::{
+        conflict::{
+            Conflict, ConflictError, ConflictPolicy, TieBreak, WinReason, compare_leaves,
+            default_tie_break, win_reason,
+        },
+        gp::Group,
+        manifest::{GroupManifest, ManifestEntry, load_group_manifest},
+        mem::Member,
+        sync::hash_file,
+    },
+    phy::cab_idx::{CabinetIndex, load_cabinet_index, scan_cabinet, write_cabinet_index},
+};
+
+#[derive(Default)]
 pub struct VirtualMember {
     pub virtual_tree: BTreeMap<PathBuf, VirtualLeaf>,
     pub highest_priority: i32,
+    // paths deleted at their srcs, with the srcs and time they were deleted at
+    pub tombstones: BTreeMap<PathBuf, ManifestEntry>,
+    // ids of the srcs holding each path, see MemberInfo::cab_id
+    pub holders: BTreeMap<PathBuf, Vec<String>>,
+    // paths some src holds but its filter leaves out, never taken as deleted
+    pub filtered: BTreeSet<PathBuf>,
+    // manifest entries that can't be judged while some src is offline
+    pub pending: BTreeMap<PathBuf, ManifestEntry>,
+    // manifest of the last sync, if any
+    pub manifest: Option<GroupManifest>,
+    // divergent versions seen while learning, judged by resolve_conflicts
+    pub versions: BTreeMap<PathBuf, Vec<VirtualLeaf>>,
+    pub conflicts: Vec<Conflict>,
+    // paths newest-wins gave to a lower priority version, with the
+    // priority of the version it won over
+    pub promoted: BTreeMap<PathBuf, i32>,
+    // why the leaf of a path won, for paths held by more than one src
+    pub resolved: BTreeMap<PathBuf, WinReason>,
 }
 
 #[derive(Debug, Clone)]
@@ -15,13 +50,44 @@ pub struct VirtualLeaf {
     pub file_rel_path: PathBuf,
     pub file_abs_path: PathBuf,
     pub priority: i32,
+    pub size: u64,
+    pub mtime: SystemTime,
+    // blake3 hex digest, only present when built with hashing
+    pub hash: Option<String>,
+    // id of the src cabinet, the last tie breaker between equal priorities
+    pub cab_id: String,
 }
 
-pub fn build_virtual_member_from_group(gp: &Group) -> VirtualMember {
-    let mut vmem = VirtualMember {
-        virtual_tree: BTreeMap::new(),
-        highest_priority: 0,
-    };
+#[derive(Debug, Clone)]
+pub struct VmemOptions {
+    // ignore cabinet indexes, read every folder and hash every file again
+    pub rescan: bool,
+    // write changed cabinet indexes back, only for commands that write anyway
+    pub save_index: bool,
+    // compute content hashes of every src file
+    pub hash: bool,
+    pub conflict_policy: ConflictPolicy,
+    // how srcs of equal priority are ordered
+    pub tie_break: Vec<TieBreak>,
+}
+
+impl Default for VmemOptions {
+    fn default() -> Self {
+        VmemOptions {
+            rescan: false,
+            save_index: false,
+            hash: false,
+            conflict_policy: ConflictPolicy::default(),
+            tie_break: default_tie_break(),
+        }
+    }
+}
+
+pub fn build_virtual_member_from_group(
+    gp: &Group,
+    vmem_opts: &VmemOptions,
+) -> Result<VirtualMember, ConflictError> {
+    let mut vmem = VirtualMember::default();
 
     let srcs = gp
         .mems
@@ -33,79 +99,90 @@ pub fn build_virtual_member_from_group(gp: &Group) -> VirtualMember {
             "learn src for virtual member {:?}",
             mem.mem_info.cab_info.abs_path
         );
-        vmem.learn(mem);
+        vmem.learn(mem, vmem_opts);
     });
+    vmem.resolve_conflicts(vmem_opts)?;
+
+    let mft = load_group_manifest(gp);
+    if let Some(mft) = &mft {
+        vmem.apply_manifest(gp, mft);
+    }
+    vmem.manifest = mft;
 
-    vmem
+    Ok(vmem)
 }
 
 impl VirtualMember {
-    pub fn learn(&mut self, src: &Member) {
+    // Priority leaf is planned with. A version promoted by newest-wins is
+    // planned just above the one it won over, so it covers every dst that
+    // one would, the priority winner's own included.
+    pub fn plan_priority(&self, leaf: &VirtualLeaf) -> i32 {
+        self.promoted
+            .get(&leaf.file_rel_path)
+            .map_or(leaf.priority, |p| p.saturating_add(1).max(leaf.priority))
+    }
+
+    pub fn learn(&mut self, src: &Member, vmem_opts: &VmemOptions) {
         if src.mem_info.mem_conf.priority > self.highest_priority {
             self.highest_priority = src.mem_info.mem_conf.priority;
         }
 
         let src_root = src.mem_info.cab_info.abs_path.as_path();
 
-        let walker = WalkBuilder::new(src_root)
-            .add_custom_ignore_filename(env!("SRC_IGNORE_NAME"))
-            .filter_entry(|ent: &DirEntry| {
-                // default: ignore cabinet config
-                !ent.file_name().to_string_lossy().eq(env!("CABINET_CONFIG_NAME"))
-            })
-            .build();
-
-        for ent in walker {
-            let ent = match ent {
-                Ok(t) => t,
-                Err(e) => {
-                    error!("Failed to read entry: {}", e);
-                    continue;
-                }
-            };
-
-            let ent_meta = match fs::symlink_metadata(ent.path()) {
-                Ok(t) => t,
-                Err(e) => {
-                    error!("Failed to read meta {}: {}", ent.path().display(), e);
-                    continue;
-                }
-            };
-
-            // symlink pass
-            if ent_meta.is_symlink() {
+        let prev_idx = Arc::new(if vmem_opts.rescan {
+            CabinetIndex::default()
+        } else {
+            load_cabinet_index(src_root)
+        });
+        let mut idx = scan_cabinet(src_root, prev_idx.clone());
+        let priority = src.mem_info.mem_conf.priority;
+        let cab_id = src.mem_info.cab_id();
+        let filter = src.file_filter();
+
+        for (file_rel_path, record) in idx.files.iter_mut() {
+            // indexed anyway, the cabinet may be a src of other groups
+            if !filter.matches(file_rel_path, record.size) {
+                self.filtered.insert(file_rel_path.clone());
                 continue;
             }
 
-            // folder pass
-            if ent_meta.is_dir() {
-                continue;
+            let file_abs_path = src_root.join(file_rel_path);
+            if vmem_opts.hash && record.hash.is_none() {
+                match hash_file(&file_abs_path) {
+                    Ok(h) => record.hash = Some(h.to_hex().to_string()),
+                    Err(e) => error!("Failed to hash {}: {}", file_abs_path.display(), e),
+                }
             }
 
-            // ent must be "file"
-            let file_abs_path = ent.path().to_path_buf();
-            let file_rel_path = match file_abs_path.strip_prefix(src_root) {
-                Ok(t) => t,
-                Err(e) => {
-                    error!(
-                        "Faild to calc relative path {} under {}: {}",
-                        file_abs_path.display(),
-                        src_root.display(),
-                        e
-                    );
-                    continue;
-                }
-            };
-            let priority = src.mem_info.mem_conf.priority;
-            self.learn_from_leaf(&VirtualLeaf {
-                file_rel_path: file_rel_path.to_path_buf(),
-                file_abs_path: file_abs_path,
-                priority: priority,
-            });
+            self.learn_from_leaf(
+                &VirtualLeaf {
+                    file_rel_path: file_rel_path.clone(),
+                    file_abs_path,
+                    priority,
+                    size: record.size,
+                    mtime: record.mtime,
+                    hash: record.hash.clone(),
+                    cab_id: cab_id.clone(),
+                },
+                &vmem_opts.tie_break,
+            );
+        }
+
+        // written only when some record changed
+        if vmem_opts.save_index
+            && idx != *prev_idx
+            && let Err(e) = write_cabinet_index(src_root, &idx)
+        {
+            warn!("Failed to write index of {}: {}", src_root.display(), e);
         }
     }
 
-    fn learn_from_leaf(&mut self, leaf: &VirtualLeaf) {
+    pub(crate) fn learn_from_leaf(&mut self, leaf: &VirtualLeaf, tie_break: &[TieBreak]) {
+        self.holders
+            .entry(leaf.file_rel_path.clone())
+            .or_default()
+            .push(leaf.cab_id.clone());
+
         let Some(exist_leaf) = self.virtual_tree.get(&leaf.file_rel_path.to_path_buf()) else {
             // learn if not exist
             self.virtual_tree
@@ -113,11 +190,33 @@ impl VirtualMember {
             return;
         };
 
-        // learn if leaf priority is higher
-        if exist_leaf.priority < leaf.priority {
+        // keep every version once the path diverged
+        let path = &leaf.file_rel_path;
+        if exist_leaf.diverges(leaf) || self.versions.contains_key(path) {
+            self.versions
+                .entry(path.clone())
+                .or_insert_with(|| vec![exist_leaf.clone()])
+                .push(leaf.clone());
+        }
+
+        // learn if leaf priority is higher, or it wins the tie. The reason
+        // kept is the one against the closest version, which comes deepest.
+        if compare_leaves(leaf, exist_leaf, tie_break).is_gt() {
+            let reason = win_reason(leaf, exist_leaf, tie_break);
+            self.resolved.insert(path.clone(), reason);
             self.virtual_tree
                 .insert(leaf.file_rel_path.clone(), leaf.clone());
-            return;
+        } else {
+            let reason = win_reason(exist_leaf, leaf, tie_break);
+            let kept = self.resolved.entry(path.clone()).or_insert(reason);
+            *kept = (*kept).max(reason);
         }
     }
+
+    // The leaf at path and why it won over the other srcs holding it.
+    pub fn resolution(&self, path: &Path) -> Option<(&VirtualLeaf, WinReason)> {
+        let leaf = self.virtual_tree.get(path)?;
+        let reason = self.resolved.get(path).copied().unwrap_or(WinReason::Only);
+        Some((leaf, reason))
+    }
 }
@@ -1,4 +1,9 @@
 pub mod cab_conf;
+pub mod cab_idx;
 pub mod cab;
 pub mod dk;
+pub mod fs_caps;
+pub mod mount;
 pub mod stg;
+pub mod stg_id;
+pub mod trash;
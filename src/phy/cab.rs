@@ -1,11 +1,20 @@
+use serde::Serialize;
 use thiserror::Error;
+use toml_edit::{DocumentMut, ImDocument, Item, TableLike, Value};
+use tracing::debug;
 
 use std::{
-    env, fs, io,
+    env,
+    ffi::OsStr,
+    fs, io,
+    ops::Range,
     path::{Path, PathBuf},
 };
 
-use crate::phy::cab_conf::{CabinetConfig, CabinetConfigError};
+use crate::phy::cab_conf::{
+    CABINET_CONFIG_VERSION, CabinetConfig, CabinetConfigError, MemberConfig,
+    default_cabinet_config, default_membership, migrate_cabinet_config,
+};
 
 #[derive(Debug, Error)]
 pub enum CabinetError {
@@ -17,45 +26,234 @@ pub enum CabinetError {
 
     #[error(transparent)]
     TomlSer(#[from] toml::ser::Error),
-    
+
+    #[error(transparent)]
+    TomlEdit(#[from] toml_edit::TomlError),
+
     #[error(transparent)]
     Config(#[from] CabinetConfigError),
+
+    #[error("In membership {membership}: {source}")]
+    Membership {
+        membership: String,
+        source: toml::de::Error,
+    },
+
+    #[error("No cabinet config at {0}")]
+    NotFound(PathBuf),
+
+    #[error("Cabinet config already exists at {0}")]
+    AlreadyExists(PathBuf),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Cabinet {
     pub cab_info: CabinetInfo,
     // relations
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CabinetInfo {
     pub abs_path: PathBuf,
     pub conf_abs_path: PathBuf,
     pub cab_conf: CabinetConfig,
 }
 
+// Parse a config of any supported version, upgraded in memory.
+// Returns the version it was written in beside it.
+pub fn parse_cabinet_config(cab_conf_str: &str) -> Result<(CabinetConfig, u32), CabinetError> {
+    let mut doc: DocumentMut = cab_conf_str.parse()?;
+    let version = migrate_cabinet_config(&mut doc)?;
+    // from text either way, so errors point at their line
+    let migrated_str = match version {
+        CABINET_CONFIG_VERSION => cab_conf_str.to_string(),
+        _ => doc.to_string(),
+    };
+    let mut cab_conf: CabinetConfig =
+        toml::from_str(&migrated_str).map_err(|e| with_membership(&migrated_str, e))?;
+    cab_conf.version = CABINET_CONFIG_VERSION;
+    Ok((cab_conf, version))
+}
+
+// Name the membership a parse error is in, ex: a level it doesn't know.
+fn with_membership(cab_conf_str: &str, e: toml::de::Error) -> CabinetError {
+    let membership = e
+        .span()
+        .and_then(|span| membership_at(cab_conf_str, span.start));
+    match membership {
+        Some(membership) => CabinetError::Membership {
+            membership,
+            source: e,
+        },
+        None => e.into(),
+    }
+}
+
+// Group uuid, and name if any, of the membership written around offset.
+fn membership_at(cab_conf_str: &str, offset: usize) -> Option<String> {
+    let doc = ImDocument::parse(cab_conf_str).ok()?;
+    let mems: Vec<(&dyn TableLike, Range<usize>)> = match doc.get("memberships")? {
+        Item::ArrayOfTables(mems) => mems
+            .iter()
+            .filter_map(|mem| {
+                let start = mem.span()?.start;
+                let end = mem.iter().filter_map(|(_, item)| span_end(item)).max()?;
+                Some((mem as &dyn TableLike, start..end))
+            })
+            .collect(),
+        Item::Value(Value::Array(mems)) => mems
+            .iter()
+            .filter_map(|mem| Some((mem.as_inline_table()? as &dyn TableLike, mem.span()?)))
+            .collect(),
+        _ => return None,
+    };
+
+    let (mem, _) = mems.into_iter().find(|(_, span)| span.contains(&offset))?;
+    let field = |key: &str| mem.get(key).and_then(Item::as_str).unwrap_or_default();
+    let (group_uuid, group_name) = (field("group_uuid"), field("group_name"));
+    if group_name.is_empty() {
+        Some(group_uuid.to_string())
+    } else {
+        Some(format!("{} ({})", group_uuid, group_name))
+    }
+}
+
+// End of the last thing written for item, sub tables included.
+fn span_end(item: &Item) -> Option<usize> {
+    match item {
+        Item::Table(table) => table
+            .iter()
+            .filter_map(|(_, item)| span_end(item))
+            .chain(table.span().map(|span| span.end))
+            .max(),
+        _ => item.span().map(|span| span.end),
+    }
+}
+
 pub fn build_cabinet_from_path(cab_abs_path: &Path) -> Result<Cabinet, CabinetError> {
+    Ok(read_cabinet(cab_abs_path)?.0)
+}
+
+// The cabinet at cab_abs_path with the version its config was written in.
+fn read_cabinet(cab_abs_path: &Path) -> Result<(Cabinet, u32), CabinetError> {
     let cab_conf_abs_path = cab_abs_path.join(env!("CABINET_CONFIG_NAME"));
-    let cab_conf_str = fs::read_to_string(cab_conf_abs_path.as_path())?;
-    let cab_conf: CabinetConfig = toml::from_str(&cab_conf_str)?;
+    let cab_conf_str = match fs::read_to_string(cab_conf_abs_path.as_path()) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(CabinetError::NotFound(cab_conf_abs_path));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let (cab_conf, version) = parse_cabinet_config(&cab_conf_str)?;
+    if version < CABINET_CONFIG_VERSION {
+        debug!(
+            "Read version {} cabinet config {}, run `rsdish cabinet migrate` to upgrade it",
+            version,
+            cab_conf_abs_path.display()
+        );
+    }
 
     cab_conf.verify()?;
 
-    Ok(Cabinet {
+    let cab = Cabinet {
         cab_info: CabinetInfo {
             abs_path: cab_abs_path.to_path_buf(),
             conf_abs_path: cab_conf_abs_path,
-            cab_conf: cab_conf,
+            cab_conf,
         },
         // stg: Weak::new(),
-    })
+    };
+    Ok((cab, version))
+}
+
+// files managed by rsdish itself, never treated as cabinet content
+pub fn is_reserved_name(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name == env!("CABINET_CONFIG_NAME")
+        || name == env!("CABINET_INDEX_NAME")
+        || name == env!("STORAGE_MARKER_NAME")
+        || name == env!("MANIFEST_DIR_NAME")
+        || name == env!("TRASH_DIR_NAME")
+        || name.ends_with(env!("SYNC_TMP_SUFFIX"))
+}
+
+pub fn write_cabinet(cab: &Cabinet) -> Result<(), CabinetError> {
+    let toml_str = toml::to_string(&cab.cab_info.cab_conf)?;
+    fs::write(&cab.cab_info.conf_abs_path, toml_str)?;
+    Ok(())
+}
+
+// Give a config made before cabinet uuids existed one. Returns whether it
+// had none.
+pub fn assign_cabinet_uuid(cab_conf: &mut CabinetConfig) -> bool {
+    if !cab_conf.cabinet_uuid.is_empty() {
+        return false;
+    }
+    cab_conf.cabinet_uuid = default_cabinet_config().cabinet_uuid;
+    true
+}
+
+// Rewrite an older config, or one without a cabinet uuid, in the current
+// version. Returns the version it was written in, none if it is up to date.
+pub fn migrate_cabinet(cab_abs_path: &Path, dry_run: bool) -> Result<Option<u32>, CabinetError> {
+    let (mut cab, version) = read_cabinet(cab_abs_path)?;
+    let assigned = assign_cabinet_uuid(&mut cab.cab_info.cab_conf);
+    if version == CABINET_CONFIG_VERSION && !assigned {
+        return Ok(None);
+    }
+    if !dry_run {
+        write_cabinet(&cab)?;
+    }
+    Ok(Some(version))
+}
+
+// Write an empty config into a dir that is not a cabinet yet.
+pub fn init_cabinet(cab_abs_path: &Path) -> Result<Cabinet, CabinetError> {
+    let cab_conf_abs_path = cab_abs_path.join(env!("CABINET_CONFIG_NAME"));
+    if cab_conf_abs_path.exists() {
+        return Err(CabinetError::AlreadyExists(cab_conf_abs_path));
+    }
+
+    let cab = Cabinet {
+        cab_info: CabinetInfo {
+            abs_path: cab_abs_path.to_path_buf(),
+            conf_abs_path: cab_conf_abs_path,
+            cab_conf: default_cabinet_config(),
+        },
+    };
+    write_cabinet(&cab)?;
+    Ok(cab)
+}
+
+// Add memberships of given groups, or of a new random group if none given.
+// Returns the joined group uuids.
+pub fn join_cabinet(cab_abs_path: &Path, gp_uuids: &[String]) -> Result<Vec<String>, CabinetError> {
+    let mut cab = build_cabinet_from_path(cab_abs_path)?;
+    let cab_conf = &mut cab.cab_info.cab_conf;
+
+    // configs made before cabinet uuids existed get one on their next change
+    assign_cabinet_uuid(cab_conf);
+
+    let new_mems: Vec<MemberConfig> = if gp_uuids.is_empty() {
+        vec![default_membership()]
+    } else {
+        gp_uuids
+            .iter()
+            .map(|uuid| MemberConfig {
+                group_uuid: uuid.clone(),
+                ..default_membership()
+            })
+            .collect()
+    };
+    let joined: Vec<String> = new_mems.iter().map(|m| m.group_uuid.clone()).collect();
+    cab_conf.memberships.extend(new_mems);
+
+    write_cabinet(&cab)?;
+    Ok(joined)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::phy::cab_conf::default_cabinet_config;
-
     use super::*;
     use std::fs;
     use tempfile::tempdir;
@@ -67,7 +265,8 @@ mod tests {
         let tmp_dir = tempdir().unwrap();
         let tmp_path = tmp_dir.path();
 
-        let test_config = default_cabinet_config();
+        let mut test_config = default_cabinet_config();
+        test_config.memberships.push(default_membership());
 
         let toml_str = toml::to_string(&test_config).unwrap();
         let cab_file_path = tmp_path.join(CABINET_CONFIG_NAME);
@@ -88,10 +287,64 @@ mod tests {
 
         Ok(())
     }
-}
 
-pub fn write_cabinet(cab: &Cabinet) -> Result<(), CabinetError> {
-    let toml_str = toml::to_string(&cab.cab_info.cab_conf)?;
-    fs::write(&cab.cab_info.conf_abs_path, toml_str)?;
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn test_parse_v1_cabinet_config() {
+        let v1_str = r#"[[memberships]]
+group_uuid = "0199ebad-44ad-78a2-baad-c56a052e33ac"
+priority = 3
+
+[memberships.src_option]
+enable = true
+
+[memberships.dst_option]
+enable = true
+cover_level = 1
+save_level = 2
+
+[memberships.link_option]
+enable = false
+save_level = 0
+"#;
+        let (cab_conf, version) = parse_cabinet_config(v1_str).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(cab_conf.version, CABINET_CONFIG_VERSION);
+
+        // migrated configs still point at the line of the original
+        let bad_str = v1_str.replace("save_level = 2", "save_level = 9");
+        let e = parse_cabinet_config(&bad_str).unwrap_err();
+        assert!(e.to_string().contains("line 11"));
+        assert!(
+            e.to_string()
+                .starts_with("In membership 0199ebad-44ad-78a2-baad-c56a052e33ac:")
+        );
+    }
+
+    #[test]
+    fn test_parse_error_names_membership() {
+        let mut cab_conf = default_cabinet_config();
+        cab_conf.memberships.push(default_membership());
+        cab_conf.memberships.push(MemberConfig {
+            group_name: "photos".to_string(),
+            ..default_membership()
+        });
+        let photos_uuid = cab_conf.memberships[1].group_uuid.clone();
+        let toml_str = toml::to_string(&cab_conf).unwrap();
+
+        // the second cover level of the file is the photos one
+        let at = toml_str.rfind("cover_level = \"none\"").unwrap();
+        let bad_str = format!(
+            "{}cover_level = \"lower\"{}",
+            &toml_str[..at],
+            &toml_str[at + "cover_level = \"none\"".len()..]
+        );
+        let e = parse_cabinet_config(&bad_str).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("In membership {} (photos):", photos_uuid)));
+        assert!(e.contains("invalid cover level \"lower\""));
+
+        // after the memberships, in no membership
+        let bad_str = toml_str.replace("[trash]\nenable = true", "[trash]\nenable = 1");
+        let e = parse_cabinet_config(&bad_str).unwrap_err().to_string();
+        assert!(e.starts_with("TOML parse error"), "{}", e);
+    }
+}
@@ -1,29 +1,108 @@
-use serde::{Deserialize, Serialize};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Deserializer, Serialize, de};
 use thiserror::Error;
+use toml_edit::{DocumentMut, Item, TableLike, Value};
 use uuid::Uuid;
 
+use std::path::Path;
+
+// Layout version written by this build. Bump it with a step in MIGRATIONS
+// whenever configs already on drives would no longer deserialize.
+pub const CABINET_CONFIG_VERSION: u32 = 2;
+
+// MIGRATIONS[i] upgrades a version i + 1 config to version i + 2.
+const MIGRATIONS: [fn(&mut DocumentMut); CABINET_CONFIG_VERSION as usize - 1] = [migrate_v1_levels];
+
 #[derive(Debug, Error)]
 pub enum CabinetConfigError {
-    #[error("Group uuid is empty")]
-    EmptyGroupUuid,
-    #[error("Invalid cover level {0}")]
-    InvalidCoverLevel(i32),
-    #[error("Invalid save level {0}")]
-    InvalidSaveLevel(i32),
+    #[error("Group uuid is empty in membership #{0}")]
+    EmptyGroupUuid(usize),
+    #[error(
+        "Invalid group name {name:?} in membership {group_uuid}, glob characters are not allowed"
+    )]
+    InvalidGroupName { group_uuid: String, name: String },
+    #[error("Invalid filter_option pattern in membership {group_uuid}: {source}")]
+    InvalidPattern {
+        group_uuid: String,
+        source: globset::Error,
+    },
+    #[error("filter_option.min_file_size is above max_file_size in membership {0}")]
+    InvalidSizeRange(String),
+    #[error(
+        "Cabinet config version {0} is newer than the supported version {CABINET_CONFIG_VERSION}, upgrade rsdish to read it"
+    )]
+    TooNew(u32),
+    #[error("Invalid cabinet config version {0}")]
+    InvalidVersion(String),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CabinetConfig {
+    // layout version, configs without one are version 1
+    #[serde(default = "first_version")]
+    pub version: u32,
+    // stable identity of the cabinet, empty for configs made before it existed
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cabinet_uuid: String,
     pub memberships: Vec<MemberConfig>,
+    #[serde(default)]
+    pub trash: TrashConfig,
+    // free space (bytes) sync always leaves on the storage, none if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free: Option<u64>,
+}
+
+// Overwritten, cleaned and quarantined files are moved into the cabinet trash
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashConfig {
+    #[serde(default = "default_true")]
+    pub enable: bool,
+    // drop entries older than this, 0 keeps them forever
+    #[serde(default = "default_trash_max_age_days")]
+    pub max_age_days: u64,
+    // drop oldest entries while the trash is larger (bytes), 0 for unbounded
+    #[serde(default = "default_trash_max_size")]
+    pub max_size: u64,
+}
+
+fn default_trash_max_age_days() -> u64 {
+    30
+}
+
+fn default_trash_max_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            enable: true,
+            max_age_days: default_trash_max_age_days(),
+            max_size: default_trash_max_size(),
+        }
+    }
+}
+
+impl TrashConfig {
+    pub fn has_limits(&self) -> bool {
+        self.max_age_days > 0 || self.max_size > 0
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MemberConfig {
     pub group_uuid: String,
+    // human friendly name of the group, kept the same across its members
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub group_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub group_description: String,
     pub priority: i32,
     pub src_option: SrcOption,
     pub dst_option: DstOption,
     pub link_option: LinkOption,
+    #[serde(default, skip_serializing_if = "FilterOption::is_empty")]
+    pub filter_option: FilterOption,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,91 +113,286 @@ pub struct SrcOption {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DstOption {
     pub enable: bool,
-    pub cover_level: i32,
-    pub save_level: i32,
+    pub cover_level: CoverLevel,
+    pub save_level: SaveLevel,
+    #[serde(default = "default_true")]
+    pub keep_mtime: bool,
+    #[serde(default = "default_true")]
+    pub keep_permissions: bool,
+    // unix only, usually requires root
+    #[serde(default)]
+    pub keep_owner: bool,
+    #[serde(default)]
+    pub compare_mode: CompareMode,
+    #[serde(default)]
+    pub delete_level: DeleteLevel,
+}
+
+// How an existing dst file is compared with its src
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompareMode {
+    // size and modification time
+    #[default]
+    Meta,
+    // size and content hash, for filesystems that mangle mtimes
+    Hash,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn first_version() -> u32 {
+    1
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LinkOption {
     pub enable: bool,
-    pub save_level: i32,
+    pub save_level: SaveLevel,
 }
 
-pub enum CoverLevel {
-    DontCover = 0,
-    HigherCover = 1,
-    Unknown,
+// Which files of the cabinet a membership holds, as src, dst and link
+// target. Globs match paths relative to the cabinet, ex: "*.raw",
+// "photos/**". No includes means every file.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FilterOption {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+    // bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_file_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
 }
 
-impl From<i32> for CoverLevel {
-    fn from(value: i32) -> Self {
-        match value {
-            0 => CoverLevel::DontCover,
-            1 => CoverLevel::HigherCover,
-            _ => CoverLevel::Unknown,
-        }
+// FilterOption with its globs compiled.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    includes: GlobSet,
+    excludes: GlobSet,
+    min_file_size: Option<u64>,
+    max_file_size: Option<u64>,
+}
+
+impl FilterOption {
+    pub fn is_empty(&self) -> bool {
+        self.includes.is_empty()
+            && self.excludes.is_empty()
+            && self.min_file_size.is_none()
+            && self.max_file_size.is_none()
+    }
+
+    pub fn build(&self) -> Result<FileFilter, globset::Error> {
+        let glob_set = |patterns: &[String]| {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern)?);
+            }
+            builder.build()
+        };
+        Ok(FileFilter {
+            includes: glob_set(&self.includes)?,
+            excludes: glob_set(&self.excludes)?,
+            min_file_size: self.min_file_size,
+            max_file_size: self.max_file_size,
+        })
+    }
+}
+
+impl FileFilter {
+    // Whether the file of size at rel_path belongs to the membership.
+    pub fn matches(&self, rel_path: &Path, size: u64) -> bool {
+        (self.includes.is_empty() || self.includes.is_match(rel_path))
+            && !self.excludes.is_match(rel_path)
+            && self.min_file_size.is_none_or(|min| size >= min)
+            && self.max_file_size.is_none_or(|max| size <= max)
     }
 }
 
+// Whether an existing dst file is overwritten by a src of higher priority
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum CoverLevel {
+    #[serde(rename = "none")]
+    DontCover,
+    #[serde(rename = "higher")]
+    HigherCover,
+}
+
+// Which missing files are saved, by src priority against the member's own
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum SaveLevel {
-    DontSave = 0,
-    SaveHigher = 1,
-    SaveHigherEqual = 2,
-    SaveAll = 3,
-    Unknown,
-}
-
-impl From<i32> for SaveLevel {
-    fn from(value: i32) -> Self {
-        match value {
-            0 => SaveLevel::DontSave,
-            1 => SaveLevel::SaveHigher,
-            2 => SaveLevel::SaveHigherEqual,
-            3 => SaveLevel::SaveAll,
-            _ => SaveLevel::Unknown,
-        }
+    #[serde(rename = "none")]
+    DontSave,
+    #[serde(rename = "higher")]
+    SaveHigher,
+    #[serde(rename = "higher-equal")]
+    SaveHigherEqual,
+    #[serde(rename = "all")]
+    SaveAll,
+}
+
+// A level as written in the config. Numbers are only read to report them,
+// configs that used them are upgraded by migrate_v1_levels.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LevelRepr {
+    Number(i64),
+    Name(String),
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>, T: Copy>(
+    deserializer: D,
+    what: &str,
+    levels: &[(&str, T)],
+) -> Result<T, D::Error> {
+    let repr = LevelRepr::deserialize(deserializer)?;
+    if let LevelRepr::Name(s) = &repr
+        && let Some((_, level)) = levels.iter().find(|(name, _)| name == s)
+    {
+        return Ok(*level);
+    }
+
+    let got = match repr {
+        LevelRepr::Number(n) => n.to_string(),
+        LevelRepr::Name(s) => format!("{:?}", s),
+    };
+    let expected: Vec<String> = levels
+        .iter()
+        .map(|(name, _)| format!("{:?}", name))
+        .collect();
+    Err(de::Error::custom(format!(
+        "invalid {} {}, expected one of {}",
+        what,
+        got,
+        expected.join(", ")
+    )))
+}
+
+impl<'de> Deserialize<'de> for CoverLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_level(
+            deserializer,
+            "cover level",
+            &[
+                ("none", CoverLevel::DontCover),
+                ("higher", CoverLevel::HigherCover),
+            ],
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for SaveLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_level(
+            deserializer,
+            "save level",
+            &[
+                ("none", SaveLevel::DontSave),
+                ("higher", SaveLevel::SaveHigher),
+                ("higher-equal", SaveLevel::SaveHigherEqual),
+                ("all", SaveLevel::SaveAll),
+            ],
+        )
+    }
+}
+
+// What happens to a dst file whose src was deleted
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeleteLevel {
+    #[default]
+    #[serde(rename = "none")]
+    DontDelete,
+    // moved to the cabinet trash
+    #[serde(rename = "quarantine")]
+    Quarantine,
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+impl<'de> Deserialize<'de> for DeleteLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_level(
+            deserializer,
+            "delete level",
+            &[
+                ("none", DeleteLevel::DontDelete),
+                ("quarantine", DeleteLevel::Quarantine),
+                ("delete", DeleteLevel::Delete),
+            ],
+        )
     }
 }
 
 pub fn default_cabinet_config() -> CabinetConfig {
     CabinetConfig {
+        version: CABINET_CONFIG_VERSION,
+        cabinet_uuid: Uuid::now_v7().to_string(),
         memberships: vec![],
+        trash: TrashConfig::default(),
+        min_free: None,
     }
 }
 
 pub fn default_membership() -> MemberConfig {
     MemberConfig {
         group_uuid: Uuid::now_v7().to_string(),
+        group_name: String::new(),
+        group_description: String::new(),
         priority: 0,
         src_option: SrcOption {
             enable: false,
         },
         dst_option: DstOption {
             enable: false,
-            cover_level: CoverLevel::DontCover as i32,
-            save_level: SaveLevel::DontSave as i32,
+            cover_level: CoverLevel::DontCover,
+            save_level: SaveLevel::DontSave,
+            keep_mtime: true,
+            keep_permissions: true,
+            keep_owner: false,
+            compare_mode: CompareMode::Meta,
+            delete_level: DeleteLevel::DontDelete,
         },
-        link_option: LinkOption { enable: false, save_level: SaveLevel::DontSave as i32 },
+        link_option: LinkOption { enable: false, save_level: SaveLevel::DontSave },
+        filter_option: FilterOption::default(),
     }
 }
 
+// Names are matched as-is by selectors, so they must not read as globs.
+pub fn is_valid_group_name(name: &str) -> bool {
+    name.trim() == name && !name.contains(['*', '?', '[', ']', '{', '}'])
+}
+
 impl CabinetConfig {
     pub fn verify(&self) -> Result<(), CabinetConfigError> {
-        self.memberships.iter().try_for_each(|m| {
+        // cover, save and delete levels are checked while parsing
+        self.memberships.iter().enumerate().try_for_each(|(i, m)| {
             if m.group_uuid.trim().is_empty() {
-                return Err(CabinetConfigError::EmptyGroupUuid);
+                return Err(CabinetConfigError::EmptyGroupUuid(i + 1));
+            }
+
+            if !is_valid_group_name(&m.group_name) {
+                return Err(CabinetConfigError::InvalidGroupName {
+                    group_uuid: m.group_uuid.clone(),
+                    name: m.group_name.clone(),
+                });
             }
 
-            if let CoverLevel::Unknown = CoverLevel::from(m.dst_option.cover_level) {
-                return Err(CabinetConfigError::InvalidCoverLevel(
-                    m.dst_option.cover_level,
-                ));
+            if let Err(source) = m.filter_option.build() {
+                return Err(CabinetConfigError::InvalidPattern {
+                    group_uuid: m.group_uuid.clone(),
+                    source,
+                });
             }
 
-            if let SaveLevel::Unknown = SaveLevel::from(m.dst_option.save_level) {
-                return Err(CabinetConfigError::InvalidSaveLevel(
-                    m.dst_option.save_level,
-                ));
+            if let (Some(min), Some(max)) =
+                (m.filter_option.min_file_size, m.filter_option.max_file_size)
+                && min > max
+            {
+                return Err(CabinetConfigError::InvalidSizeRange(m.group_uuid.clone()));
             }
             Ok(())
         })
@@ -132,8 +406,8 @@ impl CabinetConfig {
             m.src_option.enable = true;
             m.dst_option.enable = true;
             m.link_option.enable = false;
-            m.dst_option.cover_level = CoverLevel::HigherCover as i32;
-            m.dst_option.save_level = SaveLevel::SaveHigherEqual as i32;
+            m.dst_option.cover_level = CoverLevel::HigherCover;
+            m.dst_option.save_level = SaveLevel::SaveHigherEqual;
         });
     }
 
@@ -143,8 +417,8 @@ impl CabinetConfig {
             m.src_option.enable = true;
             m.dst_option.enable = true;
             m.link_option.enable = false;
-            m.dst_option.cover_level = CoverLevel::HigherCover as i32;
-            m.dst_option.save_level = SaveLevel::SaveHigherEqual as i32;
+            m.dst_option.cover_level = CoverLevel::HigherCover;
+            m.dst_option.save_level = SaveLevel::SaveHigherEqual;
         });
     }
 
@@ -154,7 +428,184 @@ impl CabinetConfig {
             m.src_option.enable = false;
             m.dst_option.enable = false;
             m.link_option.enable = true;
-            m.link_option.save_level = SaveLevel::SaveAll as i32;
+            m.link_option.save_level = SaveLevel::SaveAll;
         });
     }
-}
\ No newline at end of file
+}
+
+// Version of a config before migration.
+pub fn config_version(doc: &DocumentMut) -> Result<u32, CabinetConfigError> {
+    match doc.get("version") {
+        None => Ok(1),
+        Some(item) => item
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|v| *v >= 1)
+            .ok_or_else(|| CabinetConfigError::InvalidVersion(item.to_string().trim().to_string())),
+    }
+}
+
+// Upgrade a config of any older version to the current one, in place,
+// keeping its layout and comments. A missing version is not added, so
+// every line keeps its number. Returns the version it was written in.
+pub fn migrate_cabinet_config(doc: &mut DocumentMut) -> Result<u32, CabinetConfigError> {
+    let version = config_version(doc)?;
+    if version > CABINET_CONFIG_VERSION {
+        return Err(CabinetConfigError::TooNew(version));
+    }
+
+    for step in &MIGRATIONS[version as usize - 1..] {
+        step(doc);
+    }
+    if let Some(v) = doc.get_mut("version").and_then(Item::as_value_mut) {
+        let decor = v.decor().clone();
+        *v = Value::from(i64::from(CABINET_CONFIG_VERSION));
+        *v.decor_mut() = decor;
+    }
+    Ok(version)
+}
+
+// Memberships as tables, whether written as [[memberships]] or inline.
+fn memberships_mut(doc: &mut DocumentMut) -> Vec<&mut dyn TableLike> {
+    match doc.get_mut("memberships") {
+        Some(Item::ArrayOfTables(mems)) => mems
+            .iter_mut()
+            .map(|mem| mem as &mut dyn TableLike)
+            .collect(),
+        Some(Item::Value(Value::Array(mems))) => mems
+            .iter_mut()
+            .filter_map(Value::as_inline_table_mut)
+            .map(|mem| mem as &mut dyn TableLike)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+const SAVE_NAMES: [&str; 4] = ["none", "higher", "higher-equal", "all"];
+
+// 1 -> 2: levels are written by name instead of number.
+fn migrate_v1_levels(doc: &mut DocumentMut) {
+    name_levels(
+        doc,
+        &[
+            ("dst_option", "cover_level", &["none", "higher"]),
+            ("dst_option", "save_level", &SAVE_NAMES),
+            ("dst_option", "delete_level", &["none", "quarantine", "delete"]),
+            ("link_option", "save_level", &SAVE_NAMES),
+        ],
+    );
+}
+
+// Replace level numbers of every membership by the names they index.
+// Unknown numbers are kept, so reading the config reports them.
+fn name_levels(doc: &mut DocumentMut, fields: &[(&str, &str, &[&str])]) {
+    for mem in memberships_mut(doc) {
+        for (option, field, names) in fields {
+            let Some(level) = mem
+                .get_mut(option)
+                .and_then(Item::as_table_like_mut)
+                .and_then(|opt| opt.get_mut(field))
+                .and_then(Item::as_value_mut)
+            else {
+                continue;
+            };
+            let name = level
+                .as_integer()
+                .and_then(|n| usize::try_from(n).ok())
+                .and_then(|n| names.get(n));
+            if let Some(name) = name {
+                let decor = level.decor().clone();
+                *level = Value::from(*name);
+                *level.decor_mut() = decor;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_levels() {
+        let mut cab_conf = default_cabinet_config();
+        cab_conf.memberships.push(default_membership());
+        cab_conf.to_main();
+        let toml_str = toml::to_string(&cab_conf).unwrap();
+        assert!(toml_str.contains("save_level = \"higher-equal\""));
+
+        let conf: CabinetConfig = toml::from_str(&toml_str).unwrap();
+        let dst_option = &conf.memberships[0].dst_option;
+        assert_eq!(dst_option.cover_level, CoverLevel::HigherCover);
+        assert_eq!(dst_option.save_level, SaveLevel::SaveHigherEqual);
+
+        // numbers are left to migrate_v1_levels
+        let bad_str = toml_str.replace("save_level = \"higher-equal\"", "save_level = 2");
+        let e = toml::from_str::<CabinetConfig>(&bad_str).unwrap_err();
+        assert!(e.to_string().contains("invalid save level 2"));
+
+        let bad_str = toml_str.replace("save_level = \"higher-equal\"", "save_level = \"most\"");
+        let e = toml::from_str::<CabinetConfig>(&bad_str).unwrap_err();
+        assert!(e.to_string().contains("invalid save level \"most\""));
+
+        let bad_str = toml_str.replace("delete_level = \"none\"", "delete_level = 5");
+        let e = toml::from_str::<CabinetConfig>(&bad_str).unwrap_err();
+        assert!(e.to_string().contains("invalid delete level 5"));
+    }
+
+    #[test]
+    fn test_migrate_cabinet_config() {
+        let v1_str = r#"
+[[memberships]]
+group_uuid = "0199ebad-44ad-78a2-baad-c56a052e33ac"
+priority = 3
+
+[memberships.src_option]
+enable = true
+
+[memberships.dst_option]
+enable = true
+cover_level = 1
+save_level = 2 # saves from main and mirror
+delete_level = 1
+
+[memberships.link_option]
+enable = false
+save_level = 0
+"#;
+        let mut doc: DocumentMut = v1_str.parse().unwrap();
+        assert_eq!(migrate_cabinet_config(&mut doc).unwrap(), 1);
+        let migrated_str = doc.to_string();
+        assert!(
+            migrated_str.contains("save_level = \"higher-equal\" # saves from main and mirror")
+        );
+        let cab_conf: CabinetConfig = toml::from_str(&migrated_str).unwrap();
+        let dst_option = &cab_conf.memberships[0].dst_option;
+        assert_eq!(dst_option.save_level, SaveLevel::SaveHigherEqual);
+        assert_eq!(dst_option.delete_level, DeleteLevel::Quarantine);
+
+        let mut doc: DocumentMut = "version = 99\nmemberships = []".parse().unwrap();
+        assert!(matches!(
+            migrate_cabinet_config(&mut doc),
+            Err(CabinetConfigError::TooNew(99))
+        ));
+    }
+
+    #[test]
+    fn test_file_filter() {
+        let filter = FilterOption {
+            includes: vec!["*.raw".to_string(), "docs/**".to_string()],
+            excludes: vec!["**/tmp/**".to_string()],
+            max_file_size: Some(100),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert!(filter.matches(Path::new("2024/a.raw"), 10));
+        assert!(filter.matches(Path::new("docs/a.txt"), 10));
+        assert!(!filter.matches(Path::new("2024/a.jpg"), 10));
+        assert!(!filter.matches(Path::new("docs/tmp/a.txt"), 10));
+        assert!(!filter.matches(Path::new("2024/a.raw"), 101));
+        assert!(FileFilter::default().matches(Path::new("any"), 0));
+    }
+}
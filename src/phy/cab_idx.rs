@@ -0,0 +1,492 @@
+use ignore::{DirEntry, WalkBuilder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, warn};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::phy::cab::is_reserved_name;
+
+#[cfg(unix)]
+use std::{
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+#[derive(Debug, Error)]
+pub enum CabinetIndexError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+// ignore files that change which entries of a folder are walked
+const IGNORE_NAMES: [&str; 3] = [env!("SRC_IGNORE_NAME"), ".ignore", ".gitignore"];
+
+// Snapshot of a cabinet's files and folders from the last scan. A folder
+// whose mtime and ignore files are unchanged holds the same entries, so it
+// is not read again and its files keep their records without a stat.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CabinetIndex {
+    // key: folder path relative to cabinet root, "" for the root
+    #[serde(default, with = "index_paths")]
+    pub dirs: BTreeMap<PathBuf, DirRecord>,
+    // key: file path relative to cabinet root
+    #[serde(default, with = "index_paths")]
+    pub files: BTreeMap<PathBuf, FileRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirRecord {
+    // none for the root, which is read every time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<SystemTime>,
+    // mtimes of the ignore files in the folder
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ignores: BTreeMap<String, SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub size: u64,
+    pub mtime: SystemTime,
+    // blake3 hex digest, only recorded when hashing was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+// A relative path as text, or as raw bytes when it is not utf-8.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum IndexPath {
+    Utf8(String),
+    Raw(Vec<u8>),
+}
+
+impl From<&Path> for IndexPath {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(t) => IndexPath::Utf8(t.to_string()),
+            #[cfg(unix)]
+            None => IndexPath::Raw(path.as_os_str().as_bytes().to_vec()),
+            // windows paths are utf-16, unpaired surrogates are replaced
+            #[cfg(not(unix))]
+            None => IndexPath::Utf8(path.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+impl From<IndexPath> for PathBuf {
+    fn from(path: IndexPath) -> Self {
+        match path {
+            IndexPath::Utf8(t) => PathBuf::from(t),
+            #[cfg(unix)]
+            IndexPath::Raw(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+            #[cfg(not(unix))]
+            IndexPath::Raw(bytes) => PathBuf::from(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry<R> {
+    path: IndexPath,
+    #[serde(flatten)]
+    record: R,
+}
+
+// Records as a list of entries, json map keys can't hold raw paths.
+mod index_paths {
+    use super::*;
+    use serde::{Deserializer, Serializer, de::DeserializeOwned};
+
+    pub fn serialize<S: Serializer, R: Serialize + Clone>(
+        records: &BTreeMap<PathBuf, R>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(records.iter().map(|(path, record)| IndexEntry {
+            path: IndexPath::from(path.as_path()),
+            record: record.clone(),
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, R: DeserializeOwned>(
+        deserializer: D,
+    ) -> Result<BTreeMap<PathBuf, R>, D::Error> {
+        let entries: Vec<IndexEntry<R>> = Vec::deserialize(deserializer)?;
+        Ok(entries
+            .into_iter()
+            .map(|ent| (PathBuf::from(ent.path), ent.record))
+            .collect())
+    }
+}
+
+pub fn cabinet_index_path(cab_abs_path: &Path) -> PathBuf {
+    cab_abs_path.join(env!("CABINET_INDEX_NAME"))
+}
+
+// A missing or unreadable index is not an error, the cabinet is simply rescanned.
+pub fn load_cabinet_index(cab_abs_path: &Path) -> CabinetIndex {
+    let idx_path = cabinet_index_path(cab_abs_path);
+
+    let idx_str = match fs::read_to_string(&idx_path) {
+        Ok(t) => t,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to read index {}: {}", idx_path.display(), e);
+            }
+            return CabinetIndex::default();
+        }
+    };
+
+    serde_json::from_str(&idx_str).unwrap_or_else(|e| {
+        warn!("Discard corrupted index {}: {}", idx_path.display(), e);
+        CabinetIndex::default()
+    })
+}
+
+pub fn write_cabinet_index(
+    cab_abs_path: &Path,
+    idx: &CabinetIndex,
+) -> Result<(), CabinetIndexError> {
+    let idx_path = cabinet_index_path(cab_abs_path);
+    let tmp_path =
+        idx_path.with_extension(format!("{}{}", std::process::id(), env!("SYNC_TMP_SUFFIX")));
+
+    fs::write(&tmp_path, serde_json::to_vec(idx)?)?;
+    fs::rename(&tmp_path, &idx_path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })?;
+    Ok(())
+}
+
+impl CabinetIndex {
+    // previous record of a file
+    pub fn file(&self, rel_path: &Path) -> Option<&FileRecord> {
+        self.files.get(rel_path)
+    }
+
+    pub fn insert_file(&mut self, rel_path: PathBuf, record: FileRecord) {
+        self.files.insert(rel_path, record);
+    }
+
+    // previous record of a folder
+    pub fn dir(&self, rel_path: &Path) -> Option<&DirRecord> {
+        self.dirs.get(rel_path)
+    }
+}
+
+// Walk a cabinet, reading again only the folders changed since prev was
+// taken. A folder whose ignore files changed is read with its whole subtree.
+pub fn scan_cabinet(cab_abs_path: &Path, prev: Arc<CabinetIndex>) -> CabinetIndex {
+    let mut scan = Scan {
+        root: cab_abs_path.to_path_buf(),
+        subdirs: BTreeMap::new(),
+        reused: BTreeSet::new(),
+        idx: CabinetIndex::default(),
+        prev,
+    };
+    for rel_path in scan.prev.dirs.keys() {
+        if let Some(parent) = rel_path.parent() {
+            scan.subdirs
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(rel_path.clone());
+        }
+    }
+
+    scan.visit(Path::new(""));
+
+    // files of unchanged folders are taken as they were
+    for (rel_path, record) in scan.prev.files.iter() {
+        if rel_path.parent().is_some_and(|p| scan.reused.contains(p)) {
+            scan.idx.insert_file(rel_path.clone(), record.clone());
+        }
+    }
+    scan.idx
+}
+
+struct Scan {
+    root: PathBuf,
+    prev: Arc<CabinetIndex>,
+    // folders of prev under each folder
+    subdirs: BTreeMap<PathBuf, Vec<PathBuf>>,
+    // unchanged folders, not read again
+    reused: BTreeSet<PathBuf>,
+    idx: CabinetIndex,
+}
+
+// folders met by one walk
+#[derive(Default)]
+struct Walked {
+    dirs: Vec<(PathBuf, DirRecord)>,
+    // unchanged, left out of the walk
+    pruned: Vec<PathBuf>,
+    // ignore files changed, nothing under them is reused
+    stale: Vec<PathBuf>,
+}
+
+impl Scan {
+    // folder under an unchanged one, reused if it is unchanged too
+    fn visit(&mut self, rel_path: &Path) {
+        let prev = self.prev.dir(rel_path);
+        let Some(mut record) = stat_dir(&self.root.join(rel_path), prev) else {
+            return;
+        };
+        // writing the index changes the root mtime, so it is not kept
+        if rel_path.as_os_str().is_empty() {
+            record.mtime = None;
+        }
+
+        let stale = prev.is_some_and(|p| p.ignores != record.ignores);
+        let fresh = record.mtime.is_some() && prev == Some(&record);
+        self.idx.dirs.insert(rel_path.to_path_buf(), record);
+        if fresh {
+            self.reuse(rel_path);
+        } else {
+            self.walk(rel_path, stale);
+        }
+    }
+
+    fn reuse(&mut self, rel_path: &Path) {
+        self.reused.insert(rel_path.to_path_buf());
+        let subdirs = self.subdirs.get(rel_path).cloned().unwrap_or_default();
+        for subdir in subdirs {
+            self.visit(&subdir);
+        }
+    }
+
+    fn walk(&mut self, rel_path: &Path, stale: bool) {
+        let walked = Arc::new(Mutex::new(Walked::default()));
+        if stale {
+            walked.lock().unwrap().stale.push(rel_path.to_path_buf());
+        }
+
+        let root = self.root.clone();
+        let prev = self.prev.clone();
+        let filter_walked = walked.clone();
+        let walker = WalkBuilder::new(self.root.join(rel_path))
+            .add_custom_ignore_filename(env!("SRC_IGNORE_NAME"))
+            .filter_entry(move |ent: &DirEntry| {
+                // default: ignore cabinet config and rsdish files
+                if is_reserved_name(ent.file_name()) {
+                    return false;
+                }
+                if !ent.file_type().is_some_and(|t| t.is_dir()) {
+                    return true;
+                }
+                let Ok(dir_rel_path) = ent.path().strip_prefix(&root) else {
+                    return true;
+                };
+                let prev_record = prev.dir(dir_rel_path);
+                let Some(record) = stat_dir(ent.path(), prev_record) else {
+                    return true;
+                };
+
+                let mut walked = filter_walked.lock().unwrap();
+                let trusted = !walked.stale.iter().any(|s| dir_rel_path.starts_with(s));
+                let fresh = trusted && prev_record == Some(&record);
+                if prev_record.is_some_and(|p| p.ignores != record.ignores) {
+                    walked.stale.push(dir_rel_path.to_path_buf());
+                }
+                if fresh {
+                    walked.pruned.push(dir_rel_path.to_path_buf());
+                }
+                walked.dirs.push((dir_rel_path.to_path_buf(), record));
+                !fresh
+            })
+            .build();
+
+        for ent in walker {
+            let ent = match ent {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Failed to read entry: {}", e);
+                    continue;
+                }
+            };
+
+            // symlink and folder pass
+            let Some(ent_type) = ent.file_type() else {
+                continue;
+            };
+            if ent_type.is_symlink() || ent_type.is_dir() {
+                continue;
+            }
+
+            let file_abs_path = ent.path();
+            let file_rel_path = match file_abs_path.strip_prefix(&self.root) {
+                Ok(t) => t,
+                Err(e) => {
+                    error!(
+                        "Faild to calc relative path {} under {}: {}",
+                        file_abs_path.display(),
+                        self.root.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(record) = stat_file(file_abs_path, self.prev.file(file_rel_path)) {
+                self.idx.insert_file(file_rel_path.to_path_buf(), record);
+            }
+        }
+
+        let walked = std::mem::take(&mut *walked.lock().unwrap());
+        self.idx.dirs.extend(walked.dirs);
+        for pruned in walked.pruned {
+            self.reuse(&pruned);
+        }
+    }
+}
+
+fn stat_dir(dir_abs_path: &Path, prev: Option<&DirRecord>) -> Option<DirRecord> {
+    let mtime = match fs::metadata(dir_abs_path).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to read meta {}: {}", dir_abs_path.display(), e);
+            return None;
+        }
+    };
+
+    // an ignore file added or removed changes the folder mtime, so only the
+    // ones recorded need a look while it is unchanged
+    let names: Vec<&str> = match prev {
+        Some(p) if p.mtime == Some(mtime) => p.ignores.keys().map(String::as_str).collect(),
+        _ => IGNORE_NAMES.to_vec(),
+    };
+    let ignores = names
+        .into_iter()
+        .filter_map(|name| {
+            let mtime = fs::metadata(dir_abs_path.join(name))
+                .and_then(|m| m.modified())
+                .ok()?;
+            Some((name.to_string(), mtime))
+        })
+        .collect();
+
+    Some(DirRecord {
+        mtime: Some(mtime),
+        ignores,
+    })
+}
+
+fn stat_file(file_abs_path: &Path, prev: Option<&FileRecord>) -> Option<FileRecord> {
+    let meta = match fs::metadata(file_abs_path) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to read meta {}: {}", file_abs_path.display(), e);
+            return None;
+        }
+    };
+    let size = meta.len();
+    let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+    // a known hash stays valid as long as size and mtime are unchanged
+    let hash = prev
+        .filter(|p| p.size == size && p.mtime == mtime)
+        .and_then(|p| p.hash.clone());
+
+    Some(FileRecord { size, mtime, hash })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cabinet_index_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let record = FileRecord {
+            size: 3,
+            mtime: SystemTime::UNIX_EPOCH,
+            hash: None,
+        };
+
+        let mut idx = CabinetIndex::default();
+        idx.dirs.insert(
+            PathBuf::from("photos"),
+            DirRecord {
+                mtime: Some(SystemTime::UNIX_EPOCH),
+                ignores: BTreeMap::from([(".srcignore".to_string(), SystemTime::UNIX_EPOCH)]),
+            },
+        );
+        idx.insert_file(PathBuf::from("photos/a.jpg"), record.clone());
+        // names that are not utf-8 keep their own record
+        #[cfg(unix)]
+        let raw_path = PathBuf::from(OsString::from_vec(b"photos/\xff.jpg".to_vec()));
+        #[cfg(unix)]
+        idx.insert_file(raw_path.clone(), record.clone());
+        write_cabinet_index(tmp_dir.path(), &idx).unwrap();
+
+        let loaded = load_cabinet_index(tmp_dir.path());
+        assert_eq!(loaded, idx);
+        assert_eq!(loaded.file(Path::new("photos/a.jpg")), Some(&record));
+        #[cfg(unix)]
+        assert_eq!(loaded.file(&raw_path), Some(&record));
+    }
+
+    #[test]
+    fn test_scan_skips_unchanged_folders() {
+        let tmp_dir = tempdir().unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/x.txt"), "1").unwrap();
+        fs::write(root.join("b/y.txt"), "1").unwrap();
+        let size_of = |idx: &CabinetIndex, p: &str| idx.file(Path::new(p)).map(|r| r.size);
+
+        let first = scan_cabinet(root, Arc::default());
+        assert_eq!(size_of(&first, "a/x.txt"), Some(1));
+        assert!(first.dir(Path::new("")).is_some() && first.dir(Path::new("a")).is_some());
+
+        // an edit in place leaves a/ as it was, so x.txt is not read again,
+        // while the file added to b/ is found
+        fs::write(root.join("a/x.txt"), "12").unwrap();
+        fs::write(root.join("b/z.txt"), "1").unwrap();
+        let second = scan_cabinet(root, Arc::new(first));
+        assert_eq!(size_of(&second, "a/x.txt"), Some(1));
+        assert_eq!(size_of(&second, "b/z.txt"), Some(1));
+        assert_eq!(
+            size_of(&scan_cabinet(root, Arc::default()), "a/x.txt"),
+            Some(2)
+        );
+
+        // a new ignore file rereads the subtree below it, unchanged or not
+        fs::write(root.join(env!("SRC_IGNORE_NAME")), "y.txt").unwrap();
+        let third = scan_cabinet(root, Arc::new(second));
+        assert_eq!(size_of(&third, "a/x.txt"), Some(2));
+        assert_eq!(size_of(&third, "b/y.txt"), None);
+
+        // and so does one edited in place
+        let ignore_path = root.join(env!("SRC_IGNORE_NAME"));
+        fs::write(&ignore_path, "z.txt").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&ignore_path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let fourth = scan_cabinet(root, Arc::new(third));
+        assert_eq!(size_of(&fourth, "b/y.txt"), Some(1));
+        assert_eq!(size_of(&fourth, "b/z.txt"), None);
+    }
+
+    #[test]
+    fn test_load_corrupted_cabinet_index() {
+        let tmp_dir = tempdir().unwrap();
+        fs::write(cabinet_index_path(tmp_dir.path()), "not json").unwrap();
+
+        assert!(load_cabinet_index(tmp_dir.path()).files.is_empty());
+    }
+}
@@ -1,11 +1,188 @@
-use std::path::PathBuf;
-
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
 use sysinfo::Disks;
+use tracing::debug;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskInfo {
+    pub mount_point: PathBuf,
+    // ex: ext4, apfs, ntfs
+    pub fs_type: String,
+    pub removable: bool,
+    // bytes
+    pub available_space: u64,
+}
+
+// Mounts never holding cabinets, skipped unless explicitly included.
+pub fn default_mount_excludes() -> Vec<String> {
+    [
+        "/boot",
+        "/boot/**",
+        "/snap/**",
+        "/var/lib/**",
+        "/System/Volumes/*",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+// Pseudo and image filesystems.
+pub fn default_fs_type_excludes() -> Vec<String> {
+    [
+        "tmpfs",
+        "devtmpfs",
+        "ramfs",
+        "proc",
+        "sysfs",
+        "cgroup",
+        "cgroup2",
+        "devpts",
+        "overlay",
+        "squashfs",
+        "autofs",
+        "securityfs",
+        "debugfs",
+        "tracefs",
+        "efivarfs",
+        "fusectl",
+        "devfs",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+// Which mounted disks are scanned for cabinets. A non-empty include list
+// replaces the exclude list of the same kind.
+#[derive(Debug, Clone, Default)]
+pub struct DiskFilter {
+    pub mount_includes: GlobSet,
+    pub mount_excludes: GlobSet,
+    pub fs_type_includes: Vec<String>,
+    pub fs_type_excludes: Vec<String>,
+    // only removable (true) or only fixed (false) disks, both if none
+    pub removable: Option<bool>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+impl DiskFilter {
+    pub fn new(
+        mount_includes: &[String],
+        mount_excludes: &[String],
+        fs_type_includes: &[String],
+        fs_type_excludes: &[String],
+        removable: Option<bool>,
+    ) -> Result<Self, globset::Error> {
+        Ok(DiskFilter {
+            mount_includes: glob_set(mount_includes)?,
+            mount_excludes: glob_set(mount_excludes)?,
+            fs_type_includes: fs_type_includes.to_vec(),
+            fs_type_excludes: fs_type_excludes.to_vec(),
+            removable,
+        })
+    }
+
+    pub fn matches(&self, dk: &DiskInfo) -> bool {
+        if self
+            .removable
+            .is_some_and(|removable| removable != dk.removable)
+        {
+            return false;
+        }
+        if !self.fs_type_includes.is_empty() {
+            if !self.fs_type_includes.contains(&dk.fs_type) {
+                return false;
+            }
+        } else if self.fs_type_excludes.contains(&dk.fs_type) {
+            return false;
+        }
+        if !self.mount_includes.is_empty() {
+            return self.mount_includes.is_match(&dk.mount_point);
+        }
+        !self.mount_excludes.is_match(&dk.mount_point)
+    }
+}
 
-pub fn disks()->Vec<PathBuf> {
+// Mounted disks, refreshed once per command and passed around from there.
+pub fn disk_infos() -> Vec<DiskInfo> {
     let dks = Disks::new_with_refreshed_list();
 
     dks.iter()
-       .map(|dk|dk.mount_point().to_path_buf())
-       .collect()
-}
\ No newline at end of file
+        .map(|dk| DiskInfo {
+            mount_point: dk.mount_point().to_path_buf(),
+            fs_type: dk.file_system().to_string_lossy().into_owned(),
+            removable: dk.is_removable(),
+            available_space: dk.available_space(),
+        })
+        .collect()
+}
+
+// The disk of dks holding path, the one with the longest mount point above it.
+pub fn disk_of<'a>(dks: &'a [DiskInfo], path: &Path) -> Option<&'a DiskInfo> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    dks.iter()
+        .filter(|dk| path.starts_with(&dk.mount_point))
+        .max_by_key(|dk| dk.mount_point.as_os_str().len())
+}
+
+// Mount points of the disks of dks passing filter.
+pub fn disks(dks: &[DiskInfo], filter: &DiskFilter) -> Vec<PathBuf> {
+    dks.iter()
+        .filter(|dk| {
+            let matched = filter.matches(dk);
+            if !matched {
+                debug!("Skip mount {} ({})", dk.mount_point.display(), dk.fs_type);
+            }
+            matched
+        })
+        .map(|dk| dk.mount_point.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(mount_point: &str, fs_type: &str, removable: bool) -> DiskInfo {
+        DiskInfo {
+            mount_point: PathBuf::from(mount_point),
+            fs_type: fs_type.to_string(),
+            removable,
+            available_space: 0,
+        }
+    }
+
+    #[test]
+    fn test_disk_filter() {
+        let filter = DiskFilter::new(
+            &[],
+            &default_mount_excludes(),
+            &[],
+            &default_fs_type_excludes(),
+            None,
+        )
+        .unwrap();
+        assert!(filter.matches(&disk("/", "ext4", false)));
+        assert!(filter.matches(&disk("/media/me/Backup", "exfat", true)));
+        assert!(!filter.matches(&disk("/boot/efi", "vfat", false)));
+        assert!(!filter.matches(&disk("/run/user/1000", "tmpfs", false)));
+
+        let filter = DiskFilter {
+            removable: Some(true),
+            ..filter
+        };
+        assert!(!filter.matches(&disk("/", "ext4", false)));
+        assert!(filter.matches(&disk("/media/me/Backup", "exfat", true)));
+    }
+}
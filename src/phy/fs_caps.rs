@@ -0,0 +1,250 @@
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tracing::debug;
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Characters some filesystems refuse in file names, besides the separator.
+const NAME_CHARS_TO_PROBE: [char; 8] = ['"', '*', ':', '<', '>', '?', '\\', '|'];
+
+// What a storage filesystem can hold, probed before writing to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsCapabilities {
+    // ex: ext4, vfat, exfat, empty if unknown
+    pub fs_type: String,
+    // bytes, unbounded if none
+    pub max_file_size: Option<u64>,
+    pub symlinks: bool,
+    pub case_sensitive: bool,
+    pub invalid_chars: Vec<char>,
+    // mtimes are stored truncated to it, zero if exact
+    pub mtime_granularity: Duration,
+}
+
+// Why an entry can't be written to a filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsIncompatibility {
+    TooLarge(u64),
+    InvalidChar(char),
+    // another path differing only in case is written first or already there
+    CaseCollision(PathBuf),
+    NoSymlinks,
+}
+
+impl fmt::Display for FsIncompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsIncompatibility::TooLarge(max) => {
+                write!(f, "file is larger than the {} limit", HumanBytes(*max))
+            }
+            FsIncompatibility::InvalidChar(c) => write!(f, "name contains {:?}", c),
+            FsIncompatibility::CaseCollision(path) => {
+                write!(f, "same name as {:?} ignoring case", path)
+            }
+            FsIncompatibility::NoSymlinks => write!(f, "symlinks are not supported"),
+        }
+    }
+}
+
+impl Default for FsCapabilities {
+    fn default() -> Self {
+        FsCapabilities::from_fs_type("")
+    }
+}
+
+impl FsCapabilities {
+    // What the filesystem type usually allows, used when probing is not possible.
+    pub fn from_fs_type(fs_type: &str) -> Self {
+        let fs_type = fs_type.to_lowercase();
+        let windows_chars = NAME_CHARS_TO_PROBE.to_vec();
+        let (max_file_size, symlinks, case_sensitive, invalid_chars) = match fs_type.as_str() {
+            "vfat" | "fat" | "fat32" | "msdos" => {
+                (Some(u32::MAX as u64), false, false, windows_chars)
+            }
+            "exfat" => (None, false, false, windows_chars),
+            "ntfs" | "ntfs3" | "fuseblk" => (None, true, cfg!(not(windows)), windows_chars),
+            "apfs" | "hfs" | "hfsplus" => (None, true, false, vec![':']),
+            _ => (None, true, true, vec![]),
+        };
+        let mtime_granularity = match fs_type.as_str() {
+            "vfat" | "fat" | "fat32" | "msdos" => Duration::from_secs(2),
+            "exfat" => Duration::from_millis(10),
+            "ntfs" | "ntfs3" | "fuseblk" => Duration::from_nanos(100),
+            "hfs" | "hfsplus" => Duration::from_secs(1),
+            _ => Duration::ZERO,
+        };
+        FsCapabilities {
+            fs_type,
+            max_file_size,
+            symlinks,
+            case_sensitive,
+            invalid_chars,
+            mtime_granularity,
+        }
+    }
+
+    // Why a file of size at rel_path can't be written, none if it can.
+    pub fn check(&self, rel_path: &Path, size: u64) -> Option<FsIncompatibility> {
+        if let Some(max) = self.max_file_size
+            && size > max
+        {
+            return Some(FsIncompatibility::TooLarge(max));
+        }
+        // components, since the separator is an invalid name char on windows
+        rel_path.components().find_map(|comp| {
+            comp.as_os_str()
+                .to_string_lossy()
+                .chars()
+                .find(|c| self.invalid_chars.contains(c))
+                .map(FsIncompatibility::InvalidChar)
+        })
+    }
+
+    // Paths that would land on an earlier path of the given order, mapped to it.
+    // Empty on case-sensitive filesystems.
+    pub fn case_collisions<'a>(
+        &self,
+        rel_paths: impl IntoIterator<Item = &'a Path>,
+    ) -> BTreeMap<PathBuf, PathBuf> {
+        let mut collisions = BTreeMap::new();
+        if self.case_sensitive {
+            return collisions;
+        }
+
+        let mut seen: BTreeMap<String, &Path> = BTreeMap::new();
+        for rel_path in rel_paths {
+            let folded = rel_path.to_string_lossy().to_lowercase();
+            match seen.get(&folded) {
+                Some(first) => {
+                    collisions.insert(rel_path.to_path_buf(), first.to_path_buf());
+                }
+                None => {
+                    seen.insert(folded, rel_path);
+                }
+            }
+        }
+        collisions
+    }
+
+    // An entry under root named like a component of rel_path ignoring case
+    // but not exactly, ex: photos/ for Photos/a.jpg. Writing rel_path would
+    // land on it. Listed dirs are kept in listed, by relative path.
+    pub fn existing_case_collision(
+        &self,
+        root: &Path,
+        rel_path: &Path,
+        listed: &mut BTreeMap<PathBuf, Vec<OsString>>,
+    ) -> Option<PathBuf> {
+        if self.case_sensitive {
+            return None;
+        }
+
+        let mut dir_rel = PathBuf::new();
+        for comp in rel_path.components() {
+            let name = comp.as_os_str();
+            let folded = name.to_string_lossy().to_lowercase();
+            let names = listed.entry(dir_rel.clone()).or_insert_with(|| {
+                fs::read_dir(root.join(&dir_rel))
+                    .map(|rd| rd.flatten().map(|ent| ent.file_name()).collect())
+                    .unwrap_or_default()
+            });
+            if let Some(existing) = names
+                .iter()
+                .find(|n| *n != name && n.to_string_lossy().to_lowercase() == folded)
+            {
+                return Some(dir_rel.join(existing));
+            }
+            dir_rel.push(name);
+        }
+        None
+    }
+}
+
+// Capabilities of the filesystem of type fs_type holding stg_abs_path.
+// Symlinks, case and names are probed in a temporary folder, the size limit
+// comes from the type.
+pub fn probe_capabilities(stg_abs_path: &Path, fs_type: &str) -> FsCapabilities {
+    let mut caps = FsCapabilities::from_fs_type(fs_type);
+    if let Err(e) = probe(stg_abs_path, &mut caps) {
+        debug!(
+            "Failed to probe filesystem of {}, assume {:?}: {}",
+            stg_abs_path.display(),
+            caps.fs_type,
+            e
+        );
+    }
+    caps
+}
+
+fn probe(stg_abs_path: &Path, caps: &mut FsCapabilities) -> io::Result<()> {
+    let probe_dir = tempfile::Builder::new()
+        .prefix(".rsdish-probe-")
+        .tempdir_in(stg_abs_path)?;
+    let file = probe_dir.path().join("probe");
+    fs::write(&file, b"")?;
+
+    caps.case_sensitive = fs::symlink_metadata(probe_dir.path().join("PROBE")).is_err();
+
+    let link = probe_dir.path().join("link");
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(&file, &link);
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_file(&file, &link);
+    caps.symlinks = linked.is_ok();
+
+    // windows refuses these names on every filesystem
+    caps.invalid_chars = if cfg!(windows) {
+        NAME_CHARS_TO_PROBE.to_vec()
+    } else {
+        NAME_CHARS_TO_PROBE
+            .into_iter()
+            .filter(|c| fs::write(probe_dir.path().join(format!("probe{}", c)), b"").is_err())
+            .collect()
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fs_capabilities() {
+        let fat = FsCapabilities::from_fs_type("vfat");
+        assert_eq!(
+            fat.check(Path::new("big.iso"), 5 << 30),
+            Some(FsIncompatibility::TooLarge(u32::MAX as u64))
+        );
+        assert_eq!(
+            fat.check(Path::new("a/what?.txt"), 1),
+            Some(FsIncompatibility::InvalidChar('?'))
+        );
+        let collisions = fat.case_collisions([Path::new("Photo.JPG"), Path::new("photo.jpg")]);
+        assert_eq!(collisions[Path::new("photo.jpg")], Path::new("Photo.JPG"));
+
+        // names already on the dst count too
+        let tmp_dir = tempdir().unwrap();
+        fs::create_dir(tmp_dir.path().join("photos")).unwrap();
+        let mut listed = BTreeMap::new();
+        assert_eq!(
+            fat.existing_case_collision(tmp_dir.path(), Path::new("Photos/a.jpg"), &mut listed),
+            Some(PathBuf::from("photos"))
+        );
+        assert_eq!(
+            fat.existing_case_collision(tmp_dir.path(), Path::new("photos/a.jpg"), &mut listed),
+            None
+        );
+
+        // the probe folder is removed again
+        let tmp_dir = tempdir().unwrap();
+        probe_capabilities(tmp_dir.path(), "");
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// One line of /proc/self/mountinfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    // ex: 8:17
+    pub major: u32,
+    pub minor: u32,
+    pub mount_point: PathBuf,
+    // ex: ext4, vfat, fuseblk
+    pub fs_type: String,
+    // ex: /dev/sdb1
+    pub source: String,
+}
+
+// ex: 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+pub fn parse_mountinfo(mountinfo: &str) -> Vec<MountEntry> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (pre, post) = line.split_once(" - ")?;
+            let pre: Vec<&str> = pre.split(' ').collect();
+            let mut post = post.split(' ');
+            let (major, minor) = pre.get(2)?.split_once(':')?;
+            Some(MountEntry {
+                major: major.parse().ok()?,
+                minor: minor.parse().ok()?,
+                mount_point: PathBuf::from(unescape(pre.get(4)?)),
+                fs_type: post.next()?.to_string(),
+                source: unescape(post.next()?),
+            })
+        })
+        .collect()
+}
+
+// Spaces, tabs, newlines and backslashes are written as octal escapes.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match octal {
+            Some(b) => {
+                out.push(b);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn mounts() -> Vec<MountEntry> {
+    fs::read_to_string("/proc/self/mountinfo")
+        .map(|s| parse_mountinfo(&s))
+        .unwrap_or_default()
+}
+
+// The mount holding path, the one with the longest mount point above it.
+pub fn mount_of<'a>(mounts: &'a [MountEntry], path: &Path) -> Option<&'a MountEntry> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.as_os_str().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+97 22 8:17 / /media/me/My\\040Disk rw,nosuid shared:50 - exfat /dev/sdb1 rw,fmask=0022
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[1].mount_point, Path::new("/media/me/My Disk"));
+        assert_eq!((mounts[1].major, mounts[1].minor), (8, 17));
+        assert_eq!(mounts[1].fs_type, "exfat");
+        assert_eq!(mounts[1].source, "/dev/sdb1");
+
+        let found = mount_of(&mounts, Path::new("/media/me/My Disk/photos")).unwrap();
+        assert_eq!(found.source, "/dev/sdb1");
+        let found = mount_of(&mounts, Path::new("/media/me/My Diskette")).unwrap();
+        assert_eq!(found.source, "/dev/nvme0n1p2");
+    }
+}
@@ -1,51 +1,204 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
 use thiserror::Error;
-use tracing::error;
+use tracing::{debug, error};
 
 use std::{
-    fs,
+    fs, io,
     path::{Path, PathBuf},
 };
 
-use crate::phy::cab::{Cabinet, build_cabinet_from_path};
+use crate::phy::{
+    cab::{Cabinet, CabinetError, build_cabinet_from_path},
+    dk::{DiskInfo, disk_of},
+    fs_caps::FsCapabilities,
+    stg_id::{StorageIdentity, storage_identity},
+};
 
 #[derive(Debug, Error)]
-pub enum StorageError {}
+pub enum StorageError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
 
-#[derive(Debug, Clone)]
+    #[error("No cabinet found in storage {0}")]
+    NoCabinet(PathBuf),
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct StorageInfo {
     pub abs_path: PathBuf,
+    pub identity: StorageIdentity,
+    // device of the filesystem, only stable while it stays mounted
+    pub dev: Option<u64>,
+    // bytes free when the storage was scanned, none if unknown
+    pub available_space: Option<u64>,
+    // assumed from the fs type until probed before writing
+    pub caps: FsCapabilities,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Storage {
     pub stg_info: StorageInfo,
     // relations
     pub cabs: Vec<Cabinet>,
 }
 
-pub fn build_storages_from_paths(stg_abs_paths: &Vec<&Path>) -> Vec<Storage> {
+// Levels below a storage root searched for cabinets, 1 is its children only.
+pub fn default_discovery_depth() -> usize {
+    3
+}
+
+// Never cabinets, and not worth walking into on a system drive.
+const SYSTEM_DIR_NAMES: &[&str] = &[
+    "proc",
+    "sys",
+    "dev",
+    "run",
+    "boot",
+    "bin",
+    "sbin",
+    "lib",
+    "lib32",
+    "lib64",
+    "usr",
+    "etc",
+    "var",
+    "snap",
+    "lost+found",
+    "System",
+    "Library",
+    "private",
+    "Volumes",
+    "Windows",
+    "Program Files",
+    "Program Files (x86)",
+    "ProgramData",
+    "System Volume Information",
+    "$RECYCLE.BIN",
+];
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    pub depth: usize,
+    // dirs whose name or path matches are skipped with everything below
+    pub excludes: GlobSet,
+    // write missing storage markers, only for commands that write anyway
+    pub write_ids: bool,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            depth: default_discovery_depth(),
+            excludes: GlobSet::empty(),
+            write_ids: false,
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    pub fn new(depth: usize, excludes: &[String]) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in excludes {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(DiscoveryOptions {
+            depth,
+            excludes: builder.build()?,
+            ..Default::default()
+        })
+    }
+}
+
+pub fn build_storages_from_paths(
+    stg_abs_paths: &Vec<&Path>,
+    dks: &[DiskInfo],
+    disc_opts: &DiscoveryOptions,
+) -> Vec<Storage> {
     let mut stgs: Vec<Storage> = Vec::new();
     // ex: /System/Volumes/Samsung SSD, D:\
     for stg_abs_path in stg_abs_paths {
-        let stg_ents = match fs::read_dir(stg_abs_path) {
-            Ok(t) => t,
-            Err(e) => {
-                error!(
-                    "Failed to read storage path {}: {}",
-                    stg_abs_path.display(),
-                    e
-                );
-                continue;
-            }
-        };
+        match build_storage_from_path(stg_abs_path, dks, disc_opts) {
+            Ok(stg) => stgs.push(stg),
+            Err(StorageError::NoCabinet(_)) => (),
+            Err(e) => error!("Failed to read storage {}: {}", stg_abs_path.display(), e),
+        }
+    }
+    dedup_cabinets(stgs)
+}
+
+// A cabinet under nested storage paths belongs to the innermost one.
+fn dedup_cabinets(mut stgs: Vec<Storage>) -> Vec<Storage> {
+    let owners: Vec<(PathBuf, PathBuf)> = stgs
+        .iter()
+        .flat_map(|stg| {
+            stg.cabs
+                .iter()
+                .map(|cab| (cab.cab_info.abs_path.clone(), stg.stg_info.abs_path.clone()))
+        })
+        .collect();
+    for stg in &mut stgs {
+        let stg_len = stg.stg_info.abs_path.as_os_str().len();
+        stg.cabs.retain(|cab| {
+            !owners.iter().any(|(cab_path, stg_path)| {
+                *cab_path == cab.cab_info.abs_path && stg_path.as_os_str().len() > stg_len
+            })
+        });
+    }
+    stgs.retain(|stg| !stg.cabs.is_empty());
+    stgs
+}
+
+pub fn build_storage_from_path(
+    stg_abs_path: &Path,
+    dks: &[DiskInfo],
+    disc_opts: &DiscoveryOptions,
+) -> Result<Storage, StorageError> {
+    let mut cabs: Vec<Cabinet> = Vec::new();
+    let stg_dev = device_of(stg_abs_path);
+    let walk = DiscoveryWalk {
+        stg_abs_path,
+        stg_dev,
+        disc_opts,
+    };
+    // ex: /System/Volumes/Samsung SSD/anime123/, D:\game\
+    walk.find_cabinets(stg_abs_path, disc_opts.depth, &mut cabs)?;
+
+    if cabs.is_empty() {
+        return Err(StorageError::NoCabinet(stg_abs_path.to_path_buf()));
+    }
+
+    let dk = disk_of(dks, stg_abs_path);
+    Ok(Storage {
+        stg_info: StorageInfo {
+            abs_path: stg_abs_path.to_path_buf(),
+            identity: storage_identity(stg_abs_path, disc_opts.write_ids),
+            dev: stg_dev,
+            available_space: dk.map(|dk| dk.available_space),
+            caps: FsCapabilities::from_fs_type(dk.map_or("", |dk| &dk.fs_type)),
+        },
+        cabs,
+    })
+}
 
-        let mut cabs: Vec<Cabinet> = Vec::new();
-        // ex: /System/Volumes/Samsung SSD/anime123/, D:\game\
-        for stg_ent in stg_ents {
-            let f = match stg_ent {
+struct DiscoveryWalk<'a> {
+    stg_abs_path: &'a Path,
+    stg_dev: Option<u64>,
+    disc_opts: &'a DiscoveryOptions,
+}
+
+impl DiscoveryWalk<'_> {
+    // Cabinets below dir, up to depth levels down. Found cabinets are not
+    // searched further, a cabinet never holds another one.
+    fn find_cabinets(&self, dir: &Path, depth: usize, cabs: &mut Vec<Cabinet>) -> io::Result<()> {
+        if depth == 0 {
+            return Ok(());
+        }
+        for ent in fs::read_dir(dir)? {
+            let f = match ent {
                 Ok(t) => t,
                 Err(e) => {
-                    error!("Failed to read entry in {}: {}", stg_abs_path.display(), e);
+                    error!("Failed to read entry in {}: {}", dir.display(), e);
                     continue;
                 }
             };
@@ -58,29 +211,109 @@ pub fn build_storages_from_paths(stg_abs_paths: &Vec<&Path>) -> Vec<Storage> {
                     continue;
                 }
             };
+            if !f_type.is_dir() || self.is_skipped(dir, &f_path) {
+                continue;
+            }
 
-            if f_type.is_dir() {
-                let cab = match build_cabinet_from_path(f_path.as_path()) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!("Failed to build cabinet from {}: {}", f_path.display(), e);
-                        continue;
+            match build_cabinet_from_path(f_path.as_path()) {
+                Ok(cab) => cabs.push(cab),
+                // a plain folder, not a cabinet
+                Err(CabinetError::NotFound(_)) => {
+                    let hidden = f.file_name().to_string_lossy().starts_with('.');
+                    if depth > 1
+                        && !hidden
+                        && let Err(e) = self.find_cabinets(&f_path, depth - 1, cabs)
+                    {
+                        debug!("Skip unreadable {}: {}", f_path.display(), e);
                     }
-                };
-
-                cabs.push(cab);
+                }
+                Err(e) => error!("Failed to build cabinet from {}: {}", f_path.display(), e),
             }
         }
+        Ok(())
+    }
 
-        if cabs.len() > 0 {
-            let stg: Storage = Storage {
-                stg_info: StorageInfo {
-                    abs_path: stg_abs_path.to_path_buf(),
-                },
-                cabs: cabs,
-            };
-            stgs.push(stg);
+    fn is_skipped(&self, parent: &Path, path: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default();
+        if self.disc_opts.excludes.is_match(name) || self.disc_opts.excludes.is_match(path) {
+            return true;
         }
-    };
-    stgs
+        if parent == self.stg_abs_path
+            && SYSTEM_DIR_NAMES.contains(&name.to_string_lossy().as_ref())
+        {
+            return true;
+        }
+        // another filesystem is mounted here, it is a storage of its own
+        self.stg_dev.is_some() && device_of(path) != self.stg_dev
+    }
+}
+
+#[cfg(unix)]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path).ok().map(|meta| meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_path: &Path) -> Option<u64> {
+    None
+}
+
+impl StorageInfo {
+    // storage identity, or the mount path when the storage has none
+    pub fn stg_id(&self) -> String {
+        match self.identity.id() {
+            "" => self.abs_path.to_string_lossy().into_owned(),
+            id => id.to_string(),
+        }
+    }
+
+    // Filesystem the storage is on now, shared by storage paths on one disk.
+    pub fn fs_id(&self) -> String {
+        match (self.identity.fs_uuid.as_str(), self.dev) {
+            ("", Some(dev)) => format!("dev:{}", dev),
+            ("", None) => self.stg_id(),
+            (fs_uuid, _) => fs_uuid.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::cab::init_cabinet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_find_nested_cabinets() {
+        let tmp_dir = tempdir().unwrap();
+        let stg_root = tmp_dir.path();
+        for dir in ["family/photos/inner", "music", "cache/old", ".hidden/notes"] {
+            fs::create_dir_all(stg_root.join(dir)).unwrap();
+        }
+        for cab in [
+            "family/photos",
+            "family/photos/inner",
+            "music",
+            "cache/old",
+            ".hidden/notes",
+        ] {
+            init_cabinet(&stg_root.join(cab)).unwrap();
+        }
+
+        let disc_opts = DiscoveryOptions::new(3, &["cache".to_string()]).unwrap();
+        let stg = build_storage_from_path(stg_root, &[], &disc_opts).unwrap();
+        let mut found: Vec<&Path> = stg
+            .cabs
+            .iter()
+            .map(|cab| cab.cab_info.abs_path.strip_prefix(stg_root).unwrap())
+            .collect();
+        found.sort();
+        assert_eq!(found, [Path::new("family/photos"), Path::new("music")]);
+
+        let disc_opts = DiscoveryOptions::new(1, &[]).unwrap();
+        let stg = build_storage_from_path(stg_root, &[], &disc_opts).unwrap();
+        assert_eq!(stg.cabs.len(), 1);
+    }
 }
@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+use uuid::Uuid;
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[cfg(target_os = "linux")]
+use crate::phy::mount::{MountEntry, mount_of, mounts};
+
+#[derive(Debug, Error)]
+pub enum StorageIdError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    TomlDe(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    TomlSer(#[from] toml::ser::Error),
+}
+
+// Who a storage is, wherever it is mounted. The filesystem ids come from
+// the system, the storage uuid from a marker file at the storage root for
+// filesystems whose uuid is unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageIdentity {
+    // generated when the marker is first written
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub storage_uuid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub fs_uuid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String,
+    // serial of the device holding the filesystem
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub serial: String,
+}
+
+impl StorageIdentity {
+    // filesystem uuid, storage uuid, or empty when neither is known
+    pub fn id(&self) -> &str {
+        if !self.fs_uuid.is_empty() {
+            &self.fs_uuid
+        } else {
+            &self.storage_uuid
+        }
+    }
+}
+
+pub fn storage_marker_path(stg_abs_path: &Path) -> PathBuf {
+    stg_abs_path.join(env!("STORAGE_MARKER_NAME"))
+}
+
+pub fn read_storage_marker(stg_abs_path: &Path) -> Result<Option<StorageIdentity>, StorageIdError> {
+    match fs::read_to_string(storage_marker_path(stg_abs_path)) {
+        Ok(t) => Ok(Some(toml::from_str(&t)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_storage_marker(
+    stg_abs_path: &Path,
+    stg_id: &StorageIdentity,
+) -> Result<(), StorageIdError> {
+    fs::write(storage_marker_path(stg_abs_path), toml::to_string(stg_id)?)?;
+    Ok(())
+}
+
+// Identity of the storage at stg_abs_path. Only with write is a missing or
+// outdated marker written, so read-only commands leave the storage untouched.
+pub fn storage_identity(stg_abs_path: &Path, write: bool) -> StorageIdentity {
+    let detected = detect_fs_identity(stg_abs_path);
+    let marker = read_storage_marker(stg_abs_path).unwrap_or_else(|e| {
+        warn!(
+            "Failed to read storage marker of {}: {}",
+            stg_abs_path.display(),
+            e
+        );
+        None
+    });
+
+    let mut stg_id = marker.clone().unwrap_or_else(|| StorageIdentity {
+        storage_uuid: if write {
+            Uuid::now_v7().to_string()
+        } else {
+            String::new()
+        },
+        ..Default::default()
+    });
+    // a stale fs uuid in a copied marker must not name this filesystem
+    stg_id.fs_uuid = detected.fs_uuid;
+    // a relabelled drive keeps its storage uuid
+    for (field, value) in [
+        (&mut stg_id.label, detected.label),
+        (&mut stg_id.serial, detected.serial),
+    ] {
+        if !value.is_empty() {
+            *field = value;
+        }
+    }
+
+    if write
+        && marker.as_ref() != Some(&stg_id)
+        && let Err(e) = write_storage_marker(stg_abs_path, &stg_id)
+    {
+        warn!(
+            "Failed to write storage marker of {}: {}",
+            stg_abs_path.display(),
+            e
+        );
+        if marker.is_none() {
+            // not persisted, so not stable either
+            stg_id.storage_uuid.clear();
+        }
+    }
+    stg_id
+}
+
+#[cfg(target_os = "linux")]
+pub fn detect_fs_identity(stg_abs_path: &Path) -> StorageIdentity {
+    let mounts = mounts();
+    let Some(mount) = mount_of(&mounts, stg_abs_path) else {
+        return StorageIdentity::default();
+    };
+
+    StorageIdentity {
+        fs_uuid: find_disk_link("/dev/disk/by-uuid", mount).unwrap_or_default(),
+        label: find_disk_link("/dev/disk/by-label", mount)
+            .map(|label| unescape_udev(&label))
+            .unwrap_or_default(),
+        serial: udev_property(mount, "ID_SERIAL_SHORT")
+            .or_else(|| udev_property(mount, "ID_SERIAL"))
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn detect_fs_identity(_stg_abs_path: &Path) -> StorageIdentity {
+    StorageIdentity::default()
+}
+
+// Name of the link under dir pointing at the device of mount.
+#[cfg(target_os = "linux")]
+fn find_disk_link(dir: &str, mount: &MountEntry) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let source = fs::canonicalize(&mount.source).ok();
+    fs::read_dir(dir).ok()?.flatten().find_map(|ent| {
+        let target = fs::canonicalize(ent.path()).ok()?;
+        let same_dev = fs::metadata(&target)
+            .map(|meta| dev_major_minor(meta.rdev()) == (mount.major, mount.minor))
+            .unwrap_or(false);
+        (same_dev || source.as_ref() == Some(&target))
+            .then(|| ent.file_name().to_string_lossy().into_owned())
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn dev_major_minor(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+// ex: E:ID_SERIAL_SHORT=4C530001234 in /run/udev/data/b8:17
+#[cfg(target_os = "linux")]
+fn udev_property(mount: &MountEntry, key: &str) -> Option<String> {
+    let data =
+        fs::read_to_string(format!("/run/udev/data/b{}:{}", mount.major, mount.minor)).ok()?;
+    data.lines()
+        .filter_map(|line| line.strip_prefix("E:"))
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.to_string())
+}
+
+// udev writes unsafe characters of labels as \xHH, ex: My\x20Disk
+fn unescape_udev(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 2..i + 4)
+            .filter(|_| bytes[i..].starts_with(b"\\x"))
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        match hex {
+            Some(b) => {
+                out.push(b);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_storage_identity_is_kept() {
+        let tmp_dir = tempdir().unwrap();
+
+        // reading alone leaves no marker behind
+        assert!(
+            storage_identity(tmp_dir.path(), false)
+                .storage_uuid
+                .is_empty()
+        );
+        assert!(!storage_marker_path(tmp_dir.path()).exists());
+
+        let first = storage_identity(tmp_dir.path(), true);
+        assert!(!first.storage_uuid.is_empty());
+        let again = storage_identity(tmp_dir.path(), false);
+        assert_eq!(first.storage_uuid, again.storage_uuid);
+
+        assert_eq!(unescape_udev("My\\x20Disk"), "My Disk");
+    }
+}
@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+use uuid::Uuid;
+
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::phy::cab_conf::TrashConfig;
+
+#[derive(Debug, Error)]
+pub enum TrashError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    TomlDe(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    TomlSer(#[from] toml::ser::Error),
+
+    #[error("No trash entry matches {0}")]
+    NotFound(String),
+
+    #[error("Trash id {0} is ambiguous")]
+    Ambiguous(String),
+
+    #[error("Restore target {0} already exists")]
+    Occupied(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrashReason {
+    // replaced by a newer version during sync
+    Overwrite,
+    // removed by clean
+    Clean,
+    // deleted at src
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashMeta {
+    pub id: String,
+    pub gp_uuid: String,
+    // original path relative to cabinet root
+    pub file_rel_path: PathBuf,
+    pub trashed_at: SystemTime,
+    pub reason: TrashReason,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashEntry {
+    pub meta: TrashMeta,
+    // ex: <cab>/.rsdish-trash/<id>/
+    pub entry_abs_path: PathBuf,
+}
+
+impl fmt::Display for TrashReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TrashReason::Overwrite => "overwrite",
+            TrashReason::Clean => "clean",
+            TrashReason::Delete => "delete",
+        };
+        f.pad(s)
+    }
+}
+
+pub fn trash_dir(cab_abs_path: &Path) -> PathBuf {
+    cab_abs_path.join(env!("TRASH_DIR_NAME"))
+}
+
+impl TrashEntry {
+    pub fn data_abs_path(&self) -> PathBuf {
+        let file_name = self.meta.file_rel_path.file_name().unwrap_or_default();
+        self.entry_abs_path.join(file_name)
+    }
+}
+
+// Move a cabinet file into the cabinet trash.
+pub fn trash_file(
+    cab_abs_path: &Path,
+    gp_uuid: &str,
+    file_rel_path: &Path,
+    reason: TrashReason,
+) -> Result<TrashEntry, TrashError> {
+    let file_abs_path = cab_abs_path.join(file_rel_path);
+    let size = fs::symlink_metadata(&file_abs_path)?.len();
+
+    // uuid v7 ids sort by trash time
+    let id = Uuid::now_v7().to_string();
+    let entry = TrashEntry {
+        meta: TrashMeta {
+            id: id.clone(),
+            gp_uuid: gp_uuid.to_string(),
+            file_rel_path: file_rel_path.to_path_buf(),
+            trashed_at: SystemTime::now(),
+            reason,
+            size,
+        },
+        entry_abs_path: trash_dir(cab_abs_path).join(&id),
+    };
+
+    fs::create_dir_all(&entry.entry_abs_path)?;
+    fs::write(
+        entry.entry_abs_path.join(env!("TRASH_META_NAME")),
+        toml::to_string(&entry.meta)?,
+    )?;
+    if let Err(e) = fs::rename(&file_abs_path, entry.data_abs_path()) {
+        let _ = fs::remove_dir_all(&entry.entry_abs_path);
+        return Err(e.into());
+    }
+
+    Ok(entry)
+}
+
+// Entries of a cabinet trash, oldest first.
+pub fn list_trash(cab_abs_path: &Path) -> Result<Vec<TrashEntry>, TrashError> {
+    let trash_ents = match fs::read_dir(trash_dir(cab_abs_path)) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries: Vec<TrashEntry> = Vec::new();
+    for trash_ent in trash_ents {
+        let entry_abs_path = trash_ent?.path();
+        let meta_str = match fs::read_to_string(entry_abs_path.join(env!("TRASH_META_NAME"))) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Skip trash entry {}: {}", entry_abs_path.display(), e);
+                continue;
+            }
+        };
+        let meta: TrashMeta = match toml::from_str(&meta_str) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Skip trash entry {}: {}", entry_abs_path.display(), e);
+                continue;
+            }
+        };
+        entries.push(TrashEntry {
+            meta,
+            entry_abs_path,
+        });
+    }
+
+    entries.sort_by(|a, b| a.meta.id.cmp(&b.meta.id));
+    Ok(entries)
+}
+
+// Find an entry by id or unique id prefix.
+pub fn find_trash(cab_abs_path: &Path, id: &str) -> Result<TrashEntry, TrashError> {
+    let mut matches: Vec<TrashEntry> = list_trash(cab_abs_path)?
+        .into_iter()
+        .filter(|entry| entry.meta.id.starts_with(id))
+        .collect();
+
+    match matches.len() {
+        0 => Err(TrashError::NotFound(id.to_string())),
+        1 => Ok(matches.remove(0)),
+        _ => Err(TrashError::Ambiguous(id.to_string())),
+    }
+}
+
+// Move an entry back to its original path.
+// With overwrite, a file occupying that path is trashed first.
+pub fn restore_trash(
+    cab_abs_path: &Path,
+    entry: &TrashEntry,
+    overwrite: bool,
+) -> Result<PathBuf, TrashError> {
+    let restore_abs_path = cab_abs_path.join(&entry.meta.file_rel_path);
+
+    if fs::symlink_metadata(&restore_abs_path).is_ok() {
+        if !overwrite {
+            return Err(TrashError::Occupied(restore_abs_path));
+        }
+        trash_file(
+            cab_abs_path,
+            &entry.meta.gp_uuid,
+            &entry.meta.file_rel_path,
+            TrashReason::Overwrite,
+        )?;
+    }
+
+    if let Some(parent) = restore_abs_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(entry.data_abs_path(), &restore_abs_path)?;
+    fs::remove_dir_all(&entry.entry_abs_path)?;
+
+    Ok(restore_abs_path)
+}
+
+// Entries beyond the retention policy, oldest first.
+pub fn expired_trash(
+    cab_abs_path: &Path,
+    trash_conf: &TrashConfig,
+) -> Result<Vec<TrashEntry>, TrashError> {
+    if !trash_conf.has_limits() {
+        return Ok(vec![]);
+    }
+    let entries = list_trash(cab_abs_path)?;
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(trash_conf.max_age_days.saturating_mul(24 * 60 * 60));
+
+    let mut total_size: u64 = entries.iter().map(|e| e.meta.size).sum();
+    let mut expired: Vec<TrashEntry> = Vec::new();
+
+    for entry in entries {
+        let age = now
+            .duration_since(entry.meta.trashed_at)
+            .unwrap_or(Duration::ZERO);
+        let too_old = trash_conf.max_age_days > 0 && age > max_age;
+        let too_big = trash_conf.max_size > 0 && total_size > trash_conf.max_size;
+
+        if !too_old && !too_big {
+            continue;
+        }
+
+        total_size -= entry.meta.size;
+        expired.push(entry);
+    }
+
+    Ok(expired)
+}
+
+// Drop entries beyond the retention policy, oldest first. An entry that
+// can't be removed is logged and kept.
+pub fn prune_trash(
+    cab_abs_path: &Path,
+    trash_conf: &TrashConfig,
+) -> Result<Vec<TrashEntry>, TrashError> {
+    let mut pruned: Vec<TrashEntry> = Vec::new();
+    for entry in expired_trash(cab_abs_path, trash_conf)? {
+        if let Err(e) = fs::remove_dir_all(&entry.entry_abs_path) {
+            warn!(
+                "Failed to prune trash entry {}: {}",
+                entry.entry_abs_path.display(),
+                e
+            );
+            continue;
+        }
+        pruned.push(entry);
+    }
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_trash_and_restore() {
+        let tmp_dir = tempdir().unwrap();
+        let cab_path = tmp_dir.path();
+        fs::create_dir_all(cab_path.join("d")).unwrap();
+        fs::write(cab_path.join("d/a.txt"), "v1").unwrap();
+
+        let entry = trash_file(cab_path, "gp", Path::new("d/a.txt"), TrashReason::Clean).unwrap();
+        assert!(!cab_path.join("d/a.txt").exists());
+        assert_eq!(list_trash(cab_path).unwrap().len(), 1);
+
+        // path taken again meanwhile
+        fs::write(cab_path.join("d/a.txt"), "v2").unwrap();
+        let found = find_trash(cab_path, &entry.meta.id[..8]).unwrap();
+        assert!(matches!(
+            restore_trash(cab_path, &found, false),
+            Err(TrashError::Occupied(_))
+        ));
+
+        restore_trash(cab_path, &found, true).unwrap();
+        assert_eq!(fs::read_to_string(cab_path.join("d/a.txt")).unwrap(), "v1");
+        // v2 went to trash in place of v1
+        let entries = list_trash(cab_path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].meta.reason, TrashReason::Overwrite);
+    }
+
+    #[test]
+    fn test_prune_trash_by_size() {
+        let tmp_dir = tempdir().unwrap();
+        let cab_path = tmp_dir.path();
+        for name in ["a", "b", "c"] {
+            fs::write(cab_path.join(name), "1234").unwrap();
+            trash_file(cab_path, "gp", Path::new(name), TrashReason::Delete).unwrap();
+        }
+
+        let trash_conf = TrashConfig {
+            enable: true,
+            max_age_days: 0,
+            max_size: 8,
+        };
+        let pruned = prune_trash(cab_path, &trash_conf).unwrap();
+
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].meta.file_rel_path, Path::new("a"));
+        assert_eq!(list_trash(cab_path).unwrap().len(), 2);
+    }
+}
@@ -1 +1,2 @@
-pub mod user_conf;
\ No newline at end of file
+pub mod user_conf;
+pub mod registry;
\ No newline at end of file
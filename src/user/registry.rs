@@ -0,0 +1,345 @@
+use confy::ConfyError;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, warn};
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    logi::{
+        gp::{Group, GroupInfo},
+        report::Report,
+    },
+    user::user_conf::user_conf_path,
+};
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Config(#[from] ConfyError),
+}
+
+// Every member this machine has seen, kept beside the user config so
+// members on unplugged drives are still known.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Registry {
+    pub members: Vec<RegistryMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryMember {
+    pub gp_uuid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub group_name: String,
+    // see MemberInfo::cab_id
+    pub cab_id: String,
+    pub cab_abs_path: PathBuf,
+    pub stg_abs_path: PathBuf,
+    // absent in registries written before storages had an identity
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stg_id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stg_label: String,
+    pub priority: i32,
+    pub src: bool,
+    pub dst: bool,
+    pub link: bool,
+    pub last_seen: SystemTime,
+    pub last_synced: Option<SystemTime>,
+}
+
+// ex: ~/.config/rsdish/rsdish.registry.json
+pub fn registry_path() -> Result<PathBuf, RegistryError> {
+    Ok(user_conf_path()?.with_file_name(env!("REGISTRY_NAME")))
+}
+
+// Registry of this machine, empty when missing or unreadable.
+pub fn registry() -> Registry {
+    registry_path()
+        .and_then(|path| load_registry(&path))
+        .unwrap_or_else(|e| {
+            warn!("Failed to load member registry: {}", e);
+            Registry::default()
+        })
+}
+
+pub fn load_registry(path: &Path) -> Result<Registry, RegistryError> {
+    let reg_str = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Registry::default()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(serde_json::from_str(&reg_str)?)
+}
+
+pub fn write_registry(path: &Path, reg: &Registry) -> Result<(), RegistryError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(env!("REGISTRY_TMP_SUFFIX"));
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, serde_json::to_vec_pretty(reg)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Load, change and write back the registry, failures are only logged
+// since the registry is never required to run a command.
+pub fn update_registry(f: impl FnOnce(&mut Registry)) {
+    let result = registry_path().and_then(|path| {
+        let mut reg = load_registry(&path)?;
+        f(&mut reg);
+        write_registry(&path, &reg)
+    });
+    if let Err(e) = result {
+        error!("Failed to update member registry: {}", e);
+    }
+}
+
+impl RegistryMember {
+    // cabinet path within its storage
+    fn cab_rel_path(&self) -> Option<&Path> {
+        self.cab_abs_path.strip_prefix(&self.stg_abs_path).ok()
+    }
+
+    // whether cab_id was made from the location, see MemberInfo::cab_id
+    fn has_location_id(&self) -> bool {
+        self.cab_id
+            .strip_prefix(&self.stg_id)
+            .is_some_and(|rest| rest.starts_with(':'))
+    }
+}
+
+impl Registry {
+    // Record every online member of the groups as seen now.
+    pub fn observe<'a>(&mut self, gps: impl IntoIterator<Item = &'a Group>, now: SystemTime) {
+        for gp in gps {
+            for mem in &gp.mems {
+                let mem_conf = &mem.mem_info.mem_conf;
+                let cab_id = mem.mem_info.cab_id();
+                let cab_abs_path = &mem.mem_info.cab_info.abs_path;
+                let stg_id = mem.mem_info.stg_info.stg_id();
+                let cab_rel_path = cab_abs_path
+                    .strip_prefix(&mem.mem_info.stg_info.abs_path)
+                    .ok();
+                let has_uuid = !mem.mem_info.cab_info.cab_conf.cabinet_uuid.is_empty();
+                // a cabinet without a uuid on either side is the one at the
+                // same place on the same storage, wherever it is mounted.
+                // Members recorded before storages had an identity are keyed
+                // by their path.
+                let is_same = |m: &RegistryMember| {
+                    m.gp_uuid == gp.gp_info.gp_uuid
+                        && (m.cab_id == cab_id
+                            || ((!has_uuid || m.has_location_id())
+                                && m.stg_id == stg_id
+                                && m.cab_rel_path() == cab_rel_path)
+                            || (m.stg_id.is_empty() && m.cab_abs_path == *cab_abs_path))
+                };
+                let last_synced = self
+                    .members
+                    .iter()
+                    .filter(|m| is_same(m))
+                    .filter_map(|m| m.last_synced)
+                    .max();
+                self.members.retain(|m| !is_same(m));
+                self.members.push(RegistryMember {
+                    gp_uuid: gp.gp_info.gp_uuid.clone(),
+                    group_name: gp.gp_info.name.clone(),
+                    cab_id,
+                    cab_abs_path: cab_abs_path.clone(),
+                    stg_abs_path: mem.mem_info.stg_info.abs_path.clone(),
+                    stg_id,
+                    stg_label: mem.mem_info.stg_info.identity.label.clone(),
+                    priority: mem_conf.priority,
+                    src: mem_conf.src_option.enable,
+                    dst: mem_conf.dst_option.enable,
+                    link: mem_conf.link_option.enable,
+                    last_seen: now,
+                    last_synced,
+                });
+            }
+        }
+    }
+
+    // Online members of gp took part in a sync at synced_at. A member with
+    // a failed file in the report keeps its last good sync.
+    pub fn record_sync(&mut self, gp: &Group, report: &Report, synced_at: SystemTime) {
+        for mem in &gp.mems {
+            let cab_abs_path = &mem.mem_info.cab_info.abs_path;
+            if report
+                .failures()
+                .any(|entry| entry.target_abs_path.starts_with(cab_abs_path))
+            {
+                continue;
+            }
+            let cab_id = mem.mem_info.cab_id();
+            if let Some(reg_mem) = self
+                .members
+                .iter_mut()
+                .find(|m| m.gp_uuid == gp.gp_info.gp_uuid && m.cab_id == cab_id)
+            {
+                reg_mem.last_synced = Some(synced_at);
+            }
+        }
+    }
+
+    // Registered members of gp that are not online now.
+    pub fn offline_members(&self, gp: &Group) -> Vec<&RegistryMember> {
+        let online: Vec<String> = gp.mems.iter().map(|m| m.mem_info.cab_id()).collect();
+        self.members
+            .iter()
+            .filter(|m| m.gp_uuid == gp.gp_info.gp_uuid && !online.contains(&m.cab_id))
+            .collect()
+    }
+
+    pub fn find(&self, gp_uuid: &str, cab_id: &str) -> Option<&RegistryMember> {
+        self.members
+            .iter()
+            .find(|m| m.gp_uuid == gp_uuid && m.cab_id == cab_id)
+    }
+
+    // Groups none of whose members are online, without members.
+    pub fn offline_groups(&self, online: &BTreeMap<String, Group>) -> Vec<Group> {
+        let mut gp_map: BTreeMap<&str, Group> = BTreeMap::new();
+        for reg_mem in &self.members {
+            if online.contains_key(&reg_mem.gp_uuid) {
+                continue;
+            }
+            gp_map.entry(&reg_mem.gp_uuid).or_insert_with(|| Group {
+                gp_info: GroupInfo {
+                    gp_uuid: reg_mem.gp_uuid.clone(),
+                    name: reg_mem.group_name.clone(),
+                    ..Default::default()
+                },
+                mems: vec![],
+            });
+        }
+        gp_map.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logi::{
+            mem::{Member, MemberInfo},
+            plan::SyncAction,
+            report::{Operation, ReportEntry},
+        },
+        phy::{
+            cab::CabinetInfo,
+            cab_conf::{default_cabinet_config, default_membership},
+            stg::StorageInfo,
+        },
+    };
+    use std::time::Duration;
+
+    fn group_of(gp_uuid: &str, cab_paths: &[&str]) -> Group {
+        let mems = cab_paths
+            .iter()
+            .map(|path| Member {
+                mem_info: MemberInfo {
+                    mem_conf: default_membership(),
+                    cab_info: CabinetInfo {
+                        abs_path: PathBuf::from(path),
+                        conf_abs_path: PathBuf::from(path).join(env!("CABINET_CONFIG_NAME")),
+                        cab_conf: default_cabinet_config(),
+                    },
+                    stg_info: StorageInfo {
+                        abs_path: PathBuf::from(path),
+                        identity: Default::default(),
+                        dev: None,
+                        available_space: None,
+                        caps: Default::default(),
+                    },
+                },
+            })
+            .collect();
+        Group {
+            gp_info: GroupInfo {
+                gp_uuid: gp_uuid.to_string(),
+                ..Default::default()
+            },
+            mems,
+        }
+    }
+
+    #[test]
+    fn test_offline_members() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let t1 = t0 + Duration::from_secs(100);
+        let both = group_of("gp", &["/main", "/backup"]);
+        let mut reg = Registry::default();
+        reg.observe([&both], t0);
+        reg.record_sync(&both, &Report::new(Operation::Sync, "gp"), t0);
+
+        // a file failed on the backup, so only main counts as synced at t1
+        let mut report = Report::new(Operation::Sync, "gp");
+        report.push(
+            ReportEntry::new(SyncAction::Copy, PathBuf::from("/backup/a.txt")).failed("full"),
+        );
+        reg.record_sync(&both, &report, t1);
+        assert_eq!(
+            reg.find("gp", &both.mems[0].mem_info.cab_id())
+                .unwrap()
+                .last_synced,
+            Some(t1)
+        );
+
+        // the backup drive is unplugged
+        let main_only = Group {
+            mems: both.mems.into_iter().take(1).collect(),
+            ..group_of("gp", &[])
+        };
+        reg.observe([&main_only], t1);
+
+        let offline = reg.offline_members(&main_only);
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].cab_abs_path, Path::new("/backup"));
+        assert_eq!(offline[0].last_seen, t0);
+        assert_eq!(offline[0].last_synced, Some(t0));
+        assert_eq!(reg.members.len(), 2);
+
+        let online = BTreeMap::from([("gp".to_string(), main_only)]);
+        assert!(reg.offline_groups(&online).is_empty());
+        assert_eq!(reg.offline_groups(&BTreeMap::new()).len(), 1);
+    }
+
+    #[test]
+    fn test_observe_without_cabinet_uuid() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut gp = group_of("gp", &["/media/a/photos"]);
+        let mem_info = &mut gp.mems[0].mem_info;
+        mem_info.cab_info.cab_conf.cabinet_uuid.clear();
+        mem_info.stg_info.abs_path = PathBuf::from("/media/a");
+        mem_info.stg_info.identity.storage_uuid = "stg".to_string();
+        let mut reg = Registry::default();
+        reg.observe([&gp], t0);
+        reg.record_sync(&gp, &Report::new(Operation::Sync, "gp"), t0);
+
+        // remounted elsewhere, then given a uuid by `cabinet migrate`
+        let mem_info = &mut gp.mems[0].mem_info;
+        mem_info.cab_info.abs_path = PathBuf::from("/mnt/b/photos");
+        mem_info.stg_info.abs_path = PathBuf::from("/mnt/b");
+        reg.observe([&gp], t0);
+        gp.mems[0].mem_info.cab_info.cab_conf.cabinet_uuid = "cab".to_string();
+        reg.observe([&gp], t0);
+
+        assert_eq!(reg.members.len(), 1);
+        assert_eq!(reg.members[0].cab_id, "cab");
+        assert_eq!(reg.members[0].last_synced, Some(t0));
+    }
+}
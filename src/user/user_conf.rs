@@ -1,12 +1,62 @@
 use std::{env, path::PathBuf};
 
-use anyhow::{Ok, Result};
+use confy::ConfyError;
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+use crate::{
+    logi::conflict::{ConflictPolicy, TieBreak, default_tie_break},
+    phy::{
+        dk::{default_fs_type_excludes, default_mount_excludes},
+        stg::default_discovery_depth,
+    },
+};
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct UserConfig {
     pub custom_storages: Vec<String>,
+    // how divergent versions of a path across srcs are resolved
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    // how srcs of equal priority are ordered, the cabinet id always comes last
+    #[serde(default = "default_tie_break")]
+    pub tie_break: Vec<TieBreak>,
+    // levels below a storage root searched for cabinets
+    #[serde(default = "default_discovery_depth")]
+    pub discovery_depth: usize,
+    // globs of dir names or paths never searched for cabinets
+    #[serde(default)]
+    pub discovery_excludes: Vec<String>,
+    // globs of mount points scanned as storages, all if empty
+    #[serde(default)]
+    pub mount_includes: Vec<String>,
+    #[serde(default = "default_mount_excludes")]
+    pub mount_excludes: Vec<String>,
+    // filesystem types scanned as storages, all if empty
+    #[serde(default)]
+    pub fs_type_includes: Vec<String>,
+    #[serde(default = "default_fs_type_excludes")]
+    pub fs_type_excludes: Vec<String>,
+    // only removable (true) or only fixed (false) disks, both if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removable: Option<bool>,
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            custom_storages: vec![],
+            conflict_policy: ConflictPolicy::default(),
+            tie_break: default_tie_break(),
+            discovery_depth: default_discovery_depth(),
+            discovery_excludes: vec![],
+            mount_includes: vec![],
+            mount_excludes: default_mount_excludes(),
+            fs_type_includes: vec![],
+            fs_type_excludes: default_fs_type_excludes(),
+            removable: None,
+        }
+    }
 }
 
 // macOS: ~/Library/Application Support/<app>/<config_name>.toml
@@ -19,7 +69,6 @@ pub fn user_conf() -> UserConfig {
     })
 }
 
-pub fn user_conf_path() -> Result<PathBuf> {
-    let user_conf_path = confy::get_configuration_file_path(env!("APP_NAME"), env!("APP_CONFIG_NAME"))?;
-    Ok(user_conf_path)
+pub fn user_conf_path() -> Result<PathBuf, ConfyError> {
+    confy::get_configuration_file_path(env!("APP_NAME"), env!("APP_CONFIG_NAME"))
 }
\ No newline at end of file